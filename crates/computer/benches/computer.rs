@@ -3,6 +3,8 @@ use criterion::{criterion_group, criterion_main, Criterion};
 
 const MAX_EXPECTED_DELAY_SAMPLES: usize = 2048;
 const COMPARISON_WINDOW_WIDTH: usize = 1024;
+const LARGE_MAX_EXPECTED_DELAY_SAMPLES: usize = 16384;
+const LARGE_COMPARISON_WINDOW_WIDTH: usize = 16384;
 
 pub fn single_sample_loopback_and_delay(c: &mut Criterion) {
     c.bench_function("single sample loopback and delay", |b| {
@@ -36,6 +38,19 @@ pub fn delay(c: &mut Criterion) {
     });
 }
 
+pub fn delay_large_window(c: &mut Criterion) {
+    c.bench_function("delay large window", |b| {
+        let computer = setup_computer(
+            LARGE_MAX_EXPECTED_DELAY_SAMPLES,
+            LARGE_COMPARISON_WINDOW_WIDTH,
+        );
+
+        b.iter(|| {
+            computer.delay();
+        })
+    });
+}
+
 /// Construct SimpleComputer and run it until its internal buffers are fully populated.
 fn setup_computer(
     maximum_expected_delay_samples: usize,
@@ -55,5 +70,6 @@ criterion_group!(
     single_sample_loopback,
     single_sample_loopback_and_delay,
     delay,
+    delay_large_window,
 );
 criterion_main!(benches);
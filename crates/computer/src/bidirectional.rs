@@ -0,0 +1,225 @@
+use crate::{
+    computer::{Computer, DelayResult, SearchLock},
+    excitation::Excitation,
+    units::Setup,
+    Sample,
+};
+
+/// Two computers measuring time of flight in both directions along the same acoustic path.
+/// The forward path goes from the first speaker to the second microphone (placed next to the second
+/// speaker), the backward path from the second speaker to the first microphone.
+#[derive(Debug, Clone)]
+pub struct BidirectionalComputer {
+    forward: Computer,
+    backward: Computer,
+    setup: Setup,
+}
+
+impl BidirectionalComputer {
+    /// Both computers should play mutually uncorrelated signals, otherwise each microphone would
+    /// also pick up the other path. Both paths are assumed to have the same length and latency.
+    /// Panic when the setup doesn't specify a positive path length.
+    pub fn new(forward: Computer, backward: Computer, setup: Setup) -> Self {
+        assert!(
+            setup.path_length_m.is_some_and(|length| length > 0.0),
+            "path length must be positive"
+        );
+
+        Self {
+            forward,
+            backward,
+            setup,
+        }
+    }
+
+    /// Return the next samples for the forward and the backward speaker.
+    pub fn output_frame(&mut self) -> [Sample; 2] {
+        [self.forward.output_sample(), self.backward.output_sample()]
+    }
+
+    /// Copies of the excitations of the forward and the backward path. See
+    /// [Computer::excitation].
+    pub fn excitations(&self) -> [Box<dyn Excitation>; 2] {
+        [self.forward.excitation(), self.backward.excitation()]
+    }
+
+    /// Record played samples generated outside of the computer.
+    pub fn record_output_frame(&mut self, [forward, backward]: [Sample; 2]) {
+        self.forward.record_output_sample(forward);
+        self.backward.record_output_sample(backward);
+    }
+
+    /// Record samples from the microphones at the end of the forward and the backward path.
+    pub fn record_frame(&mut self, [forward, backward]: [Sample; 2]) {
+        self.forward.record_sample(forward);
+        self.backward.record_sample(backward);
+    }
+
+    /// See [Computer::tag_output_buffer].
+    pub fn tag_output_buffer(&mut self, playback_instant_s: f64) {
+        self.forward.tag_output_buffer(playback_instant_s);
+        self.backward.tag_output_buffer(playback_instant_s);
+    }
+
+    /// See [Computer::tag_input_buffer].
+    pub fn tag_input_buffer(&mut self, capture_instant_s: f64) {
+        self.forward.tag_input_buffer(capture_instant_s);
+        self.backward.tag_input_buffer(capture_instant_s);
+    }
+
+    pub fn measure(&self) -> Option<BidirectionalResult> {
+        self.tracking_measure(&mut [SearchLock::default(); 2])
+    }
+
+    /// Like [BidirectionalComputer::measure], with the tracking search of both computers locked
+    /// by the forward and the backward lock respectively. See [Computer::tracking_delay].
+    pub fn tracking_measure(&self, locks: &mut [SearchLock; 2]) -> Option<BidirectionalResult> {
+        let [forward_lock, backward_lock] = locks;
+        let forward = self.forward.tracking_delay(forward_lock)?;
+        let backward = self.backward.tracking_delay(backward_lock)?;
+        let wind = Wind::from_times_of_flight(
+            self.forward
+                .measurement(&forward, self.setup)
+                .time_of_flight_s(),
+            self.backward
+                .measurement(&backward, self.setup)
+                .time_of_flight_s(),
+            self.setup
+                .path_length_m
+                .expect("path length presence is checked on construction"),
+        );
+
+        Some(BidirectionalResult {
+            forward,
+            backward,
+            wind,
+        })
+    }
+
+    /// Number of frames recorded so far, see [Computer::input_sample_count]. Both microphones
+    /// are recorded frame by frame, so the paths never differ.
+    pub fn input_sample_count(&self) -> u64 {
+        self.forward.input_sample_count()
+    }
+
+    pub fn set_sample_rate_hz(&mut self, sample_rate_hz: f64) {
+        self.forward.set_sample_rate_hz(sample_rate_hz);
+        self.backward.set_sample_rate_hz(sample_rate_hz);
+    }
+
+    pub fn forward(&self) -> &Computer {
+        &self.forward
+    }
+
+    pub fn backward(&self) -> &Computer {
+        &self.backward
+    }
+
+    pub fn setup(&self) -> Setup {
+        self.setup
+    }
+}
+
+#[derive(Debug)]
+pub struct BidirectionalResult {
+    pub forward: DelayResult,
+    pub backward: DelayResult,
+    /// None when any of the delays is zero, which is physically impossible.
+    pub wind: Option<Wind>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Wind {
+    /// Component of the wind velocity along the path. Positive when blowing in forward direction.
+    pub speed_m_s: f64,
+    /// Speed of sound in still air. Independent of the wind.
+    pub speed_of_sound_m_s: f64,
+}
+
+impl Wind {
+    /// Sound travels at `c + v` forward and `c - v` backward, hence
+    /// `v = L/2·(1/t1 − 1/t2)` and `c = L/2·(1/t1 + 1/t2)`.
+    pub fn from_times_of_flight(
+        forward_s: f64,
+        backward_s: f64,
+        path_length_m: f64,
+    ) -> Option<Self> {
+        if forward_s <= 0.0 || backward_s <= 0.0 {
+            return None;
+        }
+
+        let half_length = path_length_m / 2.0;
+
+        Some(Self {
+            speed_m_s: half_length * (1.0 / forward_s - 1.0 / backward_s),
+            speed_of_sound_m_s: half_length * (1.0 / forward_s + 1.0 / backward_s),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::excitation::WhiteNoise;
+
+    #[test]
+    fn wind_from_times_of_flight() {
+        let (path_length_m, speed_of_sound_m_s, speed_m_s) = (0.5, 343.0, 5.0);
+        let wind = Wind::from_times_of_flight(
+            path_length_m / (speed_of_sound_m_s + speed_m_s),
+            path_length_m / (speed_of_sound_m_s - speed_m_s),
+            path_length_m,
+        )
+        .unwrap();
+
+        assert!((wind.speed_m_s - speed_m_s).abs() < 1e-9);
+        assert!((wind.speed_of_sound_m_s - speed_of_sound_m_s).abs() < 1e-9);
+        assert!(Wind::from_times_of_flight(0.0, 0.001, path_length_m).is_none());
+    }
+
+    #[test]
+    fn measures_both_paths() {
+        let computer = |seed| {
+            let mut computer = Computer::new(256, 1024)
+                .with_excitation(WhiteNoise::new().with_rng(ChaCha8Rng::seed_from_u64(seed)));
+            computer.set_sample_rate_hz(48_000.0);
+            computer
+        };
+        let setup = Setup {
+            path_length_m: Some(0.7),
+            system_latency_s: 0.0,
+        };
+        let mut bidirectional = BidirectionalComputer::new(computer(1), computer(2), setup);
+        let (forward_delay, backward_delay) = (96, 100);
+
+        let output: Vec<[Sample; 2]> = (0..4096).map(|_| bidirectional.output_frame()).collect();
+        for index in 0..output.len() {
+            // Each microphone also hears the other speaker right next to it.
+            let delayed = |delay: usize, path: usize| {
+                index
+                    .checked_sub(delay)
+                    .map_or(0.0, |index| output[index][path])
+            };
+            bidirectional.record_frame([
+                delayed(forward_delay, 0) + 0.5 * output[index][1],
+                delayed(backward_delay, 1) + 0.5 * output[index][0],
+            ]);
+        }
+
+        let result = bidirectional.measure().unwrap();
+        assert_eq!(result.forward.delay_samples, forward_delay);
+        assert_eq!(result.backward.delay_samples, backward_delay);
+        let expected = Wind::from_times_of_flight(
+            forward_delay as f64 / 48_000.0,
+            backward_delay as f64 / 48_000.0,
+            0.7,
+        )
+        .unwrap();
+        let wind = result.wind.unwrap();
+        assert!((wind.speed_m_s - expected.speed_m_s).abs() < 0.1);
+        assert!((wind.speed_of_sound_m_s - expected.speed_of_sound_m_s).abs() < 0.5);
+    }
+}
@@ -1,14 +1,37 @@
 use std::{
+    fmt::Debug,
+    path::PathBuf,
     sync::{Arc, RwLock},
     thread,
+    time::{Duration, SystemTime},
 };
 
 use audio_anemometer::{
-    computer::Computer, gui::run_gui, io::run_real_world_audio, simulator::simulate_audio_pipeline,
-    tui::run_tui,
+    bidirectional::BidirectionalComputer,
+    calibration::{calibrate, Calibration},
+    computer::{Computer, DEFAULT_SAMPLE_RATE_HZ},
+    correlation::Weighting,
+    drift::{compensate_clock_drift, SampleRateRatio},
+    excitation::{BandLimitedNoise, Chirp, MaximumLengthSequence, MultiTone, Sweep, WhiteNoise},
+    export::{
+        export_bidirectional_measurements, export_measurements, ExportFormat, Record, RecordWriter,
+    },
+    gui::run_gui,
+    io::{run_real_world_audio, run_real_world_bidirectional_audio, InputChannels},
+    recording::{analyze, Recording, SessionMetadata, SessionRecorder},
+    replay::{replay_recording, ReplaySpeed},
+    service::MeasurementService,
+    simulator::{simulate_audio_pipeline, simulate_bidirectional_audio_pipeline},
+    status::Status,
+    tracker::TrackerConfig,
+    tui::{run_bidirectional_tui, run_tui},
+    units::Setup,
 };
-use clap::Parser;
-use color_eyre::eyre::Result;
+use clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches};
+use color_eyre::eyre::{bail, Result, WrapErr};
+use rand::{random, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
 /// Width (in samples) of the window to use when correlating input signal with the output signal.
 const COMPARISON_WINDOW_WIDTH: usize = 1024;
@@ -17,9 +40,26 @@ const COMPARISON_WINDOW_WIDTH: usize = 1024;
 /// Used as a cap for compute and memory usage.
 const MAX_EXPECTED_DELAY_SAMPLES: usize = 2048;
 
+/// Frequency range of the band-limited noise and chirps. Roughly what small speakers can reproduce.
+const EXCITATION_LOW_FREQUENCY_HZ: f64 = 500.0;
+const EXCITATION_HIGH_FREQUENCY_HZ: f64 = 12_000.0;
+/// Default order of the maximum-length sequence. Its period (2^order - 1) is both the comparison
+/// window and the maximum expected delay.
+const MLS_ORDER: u32 = 11;
+/// Order of the Golay complementary pair. Both sequences are 2^order samples long, which is also
+/// the longest delay the pair can measure.
+const GOLAY_ORDER: u32 = 11;
+/// Length of a single chirp sweep.
+const CHIRP_DURATION_SAMPLES: usize = 4096;
+/// Frequencies of the multi-tone signal. Mutually prime-ish to make its period long.
+const MULTI_TONE_FREQUENCIES_HZ: [f64; 6] = [701.0, 1303.0, 2411.0, 3907.0, 6007.0, 9011.0];
+
 /// By how many samples the simulator delays the produced input (as if coming from microphone)
 /// compared to the output (as if fed to speakers).
 pub const SIMULATED_DELAY_SAMPLES: usize = 139;
+/// Delay of the simulated backward path. Differs from the forward one as if the wind blew along
+/// the path.
+const SIMULATED_BACKWARD_DELAY_SAMPLES: usize = 141;
 /// How much does the simulator attenuates the signal. (applied as a multiplier to every sample)
 const SIMULATED_GAIN: f32 = 1.0;
 /// Signal to noise ratio of the simulated physical system.
@@ -27,13 +67,90 @@ const SIMULATED_SNR: f32 = 5.0;
 
 #[derive(Debug, Clone, clap::Subcommand)]
 enum Command {
-    Simulate,
+    Simulate {
+        /// Write the played and the recorded samples to WAV files in this directory, together with
+        /// a description of the session.
+        #[arg(long)]
+        record: Option<PathBuf>,
+    },
     Run {
         #[arg(long, short)]
         input_device: Option<String>,
         #[arg(long, short)]
         output_device: Option<String>,
+        /// Input channel with the acoustic microphone.
+        #[arg(long, default_value_t = 0)]
+        microphone_channel: usize,
+        /// Input channel with an electrical loopback of the output. When given, the acoustic delay
+        /// is measured relative to the loopback, which cancels out the device latency.
+        #[arg(long)]
+        loopback_channel: Option<usize>,
+        /// Estimate how fast the input and output device clocks drift apart and resample the input
+        /// to match the output. Useful when the microphone and the speaker are separate devices.
+        #[arg(long)]
+        compensate_drift: bool,
+        /// Write the played and the recorded samples to WAV files in this directory, together with
+        /// a description of the session.
+        #[arg(long)]
+        record: Option<PathBuf>,
+    },
+    /// Measure system latency under known conditions (still air, known path length and
+    /// temperature) and store it in the calibration file. Later runs subtract it automatically.
+    Calibrate {
+        #[arg(long, short)]
+        input_device: Option<String>,
+        #[arg(long, short)]
+        output_device: Option<String>,
+        /// How long to measure for.
+        #[arg(long, default_value_t = 10.0)]
+        duration_s: f64,
+        /// Temperature of the air during calibration. Used to compute the expected time of flight.
+        #[arg(long, default_value_t = 20.0)]
+        temperature_c: f64,
     },
+    /// Measure delays in previously recorded WAV files instead of live audio and write them in
+    /// the --export format, CSV by default.
+    Analyze {
+        /// WAV file with the recorded signal. Without --reference it must be a stereo file with the
+        /// played signal in the first channel and the recorded one in the second.
+        recorded: PathBuf,
+        /// WAV file with the played signal.
+        #[arg(long)]
+        reference: Option<PathBuf>,
+        /// Where to write the delay time series. Defaults to the standard output.
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// How many samples to advance between measurements. Defaults to the comparison window.
+        #[arg(long)]
+        hop_samples: Option<usize>,
+    },
+    /// Feed a session recorded with --record to the computer as if it was live audio. Every
+    /// replay of the same session measures the same delays.
+    Replay {
+        /// Directory the session was recorded to.
+        directory: PathBuf,
+        /// Multiple of real-time pace to replay at.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Replay as fast as the delays can be computed.
+        #[arg(long, conflicts_with = "speed")]
+        as_fast_as_possible: bool,
+        /// How many samples to advance between measurements. Defaults to the comparison window.
+        #[arg(long)]
+        hop_samples: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ExcitationKind {
+    WhiteNoise,
+    BandLimitedNoise,
+    Mls,
+    Golay,
+    LinearChirp,
+    ExponentialChirp,
+    MultiTone,
 }
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -42,24 +159,366 @@ struct Args {
     command: Command,
     #[arg(long)]
     run_gui: bool,
+    /// Generalized cross-correlation weighting used to find the delay.
+    #[arg(long, value_enum, default_value_t = Weighting::default())]
+    weighting: Weighting,
+    /// Signal to play from the speaker.
+    #[arg(long, value_enum, default_value_t = ExcitationKind::WhiteNoise)]
+    excitation: ExcitationKind,
+    /// Order of the maximum-length sequence used by the `mls` excitation.
+    #[arg(long, default_value_t = MLS_ORDER)]
+    mls_order: u32,
+    /// Once locked on a delay, search only this many samples around it. Lock on requires the
+    /// minimum confidence.
+    #[arg(long)]
+    tracking_window: Option<usize>,
+    /// Ignore delay measurements with confidence (0..=1) lower than this.
+    #[arg(long, default_value_t = 0.0)]
+    minimum_confidence: f64,
+    /// Measure time of flight in both directions and compute wind speed. Uses first two output
+    /// channels as the forward and the backward speaker and first two input channels as the
+    /// forward and the backward microphone.
+    #[arg(long, requires = "path_length")]
+    bidirectional: bool,
+    /// Distance (in meters) between the speaker and the microphone.
+    #[arg(long)]
+    path_length: Option<f64>,
+    /// Latency (in milliseconds) of the audio interface, i.e. delay that isn't time of flight.
+    /// Overrides the latency from the calibration file.
+    #[arg(long)]
+    system_latency_ms: Option<f64>,
+    /// Where the `calibrate` command stores the system latency and `run` loads it from.
+    #[arg(long, default_value = "calibration.json")]
+    calibration_file: PathBuf,
+    /// Width (in samples) of the buckets of the TUI's delay histogram.
+    #[arg(long, default_value_t = 1.0)]
+    histogram_bucket_samples: f64,
+    /// Write every measurement in this format, for other programs to read. Replaces the TUI
+    /// unless written to --export-file.
+    #[arg(long, value_enum)]
+    export: Option<ExportFormat>,
+    /// Where to write the --export measurements. Defaults to the standard output.
+    #[arg(long, requires = "export")]
+    export_file: Option<PathBuf>,
+    /// Seed of the random number generators of the excitation and the simulator. Pass the seed a
+    /// previous run printed to repeat it. Random by default.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl Args {
+    /// The given seed or a random one. Printed so that the run can be repeated.
+    fn seed(&self) -> u64 {
+        let seed = self.seed.unwrap_or_else(random);
+        eprintln!("seed: {seed}");
+        seed
+    }
+
+    fn record_directory(&self) -> Option<&PathBuf> {
+        match &self.command {
+            Command::Simulate { record } | Command::Run { record, .. } => record.as_ref(),
+            Command::Calibrate { .. } | Command::Analyze { .. } | Command::Replay { .. } => None,
+        }
+    }
+
+    /// Calibration to take the system latency from: the calibration file when running with
+    /// real-world audio, unless the latency is given on the command line.
+    fn calibration(&self) -> Result<Option<Calibration>> {
+        match (self.system_latency_ms, &self.command) {
+            (None, Command::Run { .. }) if self.calibration_file.exists() => {
+                Calibration::load(&self.calibration_file).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Take system latency from the command line or from the calibration. The simulator has no
+    /// latency.
+    fn setup(&self, calibration: Option<&Calibration>) -> Setup {
+        let system_latency_s = match (self.system_latency_ms, calibration) {
+            (Some(system_latency_ms), _) => system_latency_ms / 1000.0,
+            (None, Some(calibration)) => {
+                eprintln!(
+                    "applying system latency {:.3} ± {:.3} ms from {}",
+                    calibration.system_latency_s * 1000.0,
+                    calibration.system_latency_std_s * 1000.0,
+                    self.calibration_file.display()
+                );
+                calibration.system_latency_s
+            }
+            (None, None) => 0.0,
+        };
+
+        Setup {
+            path_length_m: self.path_length,
+            system_latency_s,
+        }
+    }
+
+    /// Configure the replay like the session was recorded, see [Parameters]. Options given on the
+    /// command line win over the recorded ones, with a warning. Fail when the session was recorded
+    /// with different constants, which the command line can't override.
+    fn apply_recorded_parameters(
+        &mut self,
+        parameters: &Parameters,
+        matches: &ArgMatches,
+    ) -> Result<()> {
+        for (name, recorded, constant) in [
+            (
+                "comparison window width",
+                parameters.comparison_window_width,
+                COMPARISON_WINDOW_WIDTH,
+            ),
+            (
+                "maximum expected delay",
+                parameters.max_expected_delay_samples,
+                MAX_EXPECTED_DELAY_SAMPLES,
+            ),
+        ] {
+            if recorded != constant {
+                bail!("session was recorded with {name} {recorded}, this build uses {constant}");
+            }
+        }
+
+        apply_recorded(
+            &mut self.excitation,
+            "excitation",
+            parameters.excitation,
+            matches,
+        );
+        apply_recorded(
+            &mut self.weighting,
+            "weighting",
+            parameters.weighting,
+            matches,
+        );
+        apply_recorded(
+            &mut self.mls_order,
+            "mls_order",
+            parameters.mls_order,
+            matches,
+        );
+        apply_recorded(
+            &mut self.tracking_window,
+            "tracking_window",
+            parameters.tracking_window,
+            matches,
+        );
+        apply_recorded(
+            &mut self.minimum_confidence,
+            "minimum_confidence",
+            parameters.minimum_confidence,
+            matches,
+        );
+        apply_recorded(
+            &mut self.path_length,
+            "path_length",
+            parameters.path_length_m,
+            matches,
+        );
+        // The effective latency, calibration included, is recorded in seconds.
+        apply_recorded(
+            &mut self.system_latency_ms,
+            "system_latency_ms",
+            Some(parameters.system_latency_s * 1000.0),
+            matches,
+        );
+
+        Ok(())
+    }
+}
+
+/// Set the argument with given id to the recorded value, unless the argument was given on the
+/// command line.
+fn apply_recorded<T: PartialEq + Debug>(
+    field: &mut T,
+    id: &str,
+    recorded: T,
+    matches: &ArgMatches,
+) {
+    if matches.value_source(id) != Some(ValueSource::CommandLine) {
+        *field = recorded;
+    } else if *field != recorded {
+        eprintln!(
+            "replaying with --{} {field:?} instead of the recorded {recorded:?}",
+            id.replace('_', "-")
+        );
+    }
+}
+
+/// Configuration and constants a session ran with, stored in the metadata of its recording.
+/// Replays read them back, see [Args::apply_recorded_parameters].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Parameters {
+    /// `simulate` or `run`.
+    command: String,
+    excitation: ExcitationKind,
+    weighting: Weighting,
+    mls_order: u32,
+    tracking_window: Option<usize>,
+    minimum_confidence: f64,
+    path_length_m: Option<f64>,
+    /// Effective latency, the calibrated one included.
+    system_latency_s: f64,
+    comparison_window_width: usize,
+    max_expected_delay_samples: usize,
+    /// The simulator's constants, None with real-world audio.
+    simulated_delay_samples: Option<usize>,
+    simulated_gain: Option<f32>,
+    simulated_snr: Option<f32>,
+}
+
+impl Parameters {
+    fn new(args: &Args, setup: Setup) -> Self {
+        let simulated = matches!(args.command, Command::Simulate { .. });
+
+        Self {
+            command: if simulated { "simulate" } else { "run" }.to_string(),
+            excitation: args.excitation,
+            weighting: args.weighting,
+            mls_order: args.mls_order,
+            tracking_window: args.tracking_window,
+            minimum_confidence: args.minimum_confidence,
+            path_length_m: args.path_length,
+            system_latency_s: setup.system_latency_s,
+            comparison_window_width: COMPARISON_WINDOW_WIDTH,
+            max_expected_delay_samples: MAX_EXPECTED_DELAY_SAMPLES,
+            simulated_delay_samples: simulated.then_some(SIMULATED_DELAY_SAMPLES),
+            simulated_gain: simulated.then_some(SIMULATED_GAIN),
+            simulated_snr: simulated.then_some(SIMULATED_SNR),
+        }
+    }
 }
 
 fn main() -> Result<()> {
     color_eyre::install()?;
 
-    let args = Args::parse();
+    // The matches tell which of the arguments were given explicitly, see
+    // Args::apply_recorded_parameters().
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
 
-    let computer = Arc::new(RwLock::new(Computer::new(
-        MAX_EXPECTED_DELAY_SAMPLES,
-        COMPARISON_WINDOW_WIDTH,
-    )));
+    if args.histogram_bucket_samples <= 0.0 {
+        bail!("histogram buckets must have positive width");
+    }
+
+    // Messages of the background threads, shown by the TUI instead of printed while it runs.
+    let status = Status::new();
+
+    if args.bidirectional {
+        return run_bidirectional(args, status);
+    }
+
+    if let Command::Analyze { .. } = args.command {
+        return run_analysis(args);
+    }
+
+    if let Command::Replay { directory, .. } = &args.command {
+        let metadata = SessionMetadata::load(directory)?;
+        let parameters = serde_json::from_value(metadata.parameters)
+            .wrap_err_with(|| format!("reading the parameters of {}", directory.display()))?;
+        args.apply_recorded_parameters(&parameters, &matches)?;
+    }
+
+    let seed = args.seed();
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut computer = build_computer(&args, &mut rng)?;
+    if let Command::Run {
+        loopback_channel: Some(_),
+        ..
+    } = args.command
+    {
+        computer = computer.with_loopback();
+    }
+    let calibration = args.calibration()?;
+    let setup = args.setup(calibration.as_ref());
+    let recorder = match args.record_directory() {
+        Some(directory) => Some(SessionRecorder::new(
+            directory.clone(),
+            session_metadata(&args, seed, setup),
+            status.clone(),
+        )?),
+        None => None,
+    };
+    if let Some(recorder) = recorder.clone() {
+        computer = computer.with_recorder(recorder);
+    }
+    let replay = match &args.command {
+        Command::Replay {
+            directory,
+            speed,
+            as_fast_as_possible,
+            hop_samples,
+        } => {
+            let recording = Recording::from_session(directory)?;
+            if recording.loopback.is_some() {
+                computer = computer.with_loopback();
+            }
+            let hop_samples = hop_samples.unwrap_or(computer.input_buffer().capacity());
+            let speed = if *as_fast_as_possible {
+                ReplaySpeed::AsFastAsPossible
+            } else if *speed > 0.0 {
+                ReplaySpeed::Factor(*speed)
+            } else {
+                bail!("replay speed must be positive");
+            };
+            // The replay drives a computer of its own, see replay_recording().
+            Some((computer.clone(), recording, hop_samples, speed))
+        }
+        _ => None,
+    };
+    let computer = Arc::new(RwLock::new(computer));
+
+    if let Command::Calibrate {
+        input_device,
+        output_device,
+        duration_s,
+        temperature_c,
+    } = args.command
+    {
+        let _streams = run_real_world_audio(
+            Arc::clone(&computer),
+            input_device,
+            output_device,
+            InputChannels::default(),
+            None,
+            status,
+        )?;
+        // Calibration measures the total delay, the setup doesn't matter.
+        let service = MeasurementService::spawn(computer, Setup::default());
+        let calibration = calibrate(
+            service.subscribe(),
+            Duration::from_secs_f64(duration_s),
+            args.path_length,
+            temperature_c,
+            args.minimum_confidence,
+        )?;
+        calibration.save(&args.calibration_file)?;
+
+        println!(
+            "system latency {:.3} ± {:.3} ms (from {} measurements) saved to {}",
+            calibration.system_latency_s * 1000.0,
+            calibration.system_latency_std_s * 1000.0,
+            calibration.measurements,
+            args.calibration_file.display()
+        );
+        return Ok(());
+    }
 
-    let simulator = matches!(args.command, Command::Simulate).then(|| {
+    let service = if replay.is_some() {
+        // The replay publishes the analyses itself.
+        MeasurementService::new()
+    } else {
+        MeasurementService::spawn(Arc::clone(&computer), setup)
+    };
+
+    let simulator = matches!(args.command, Command::Simulate { .. }).then(|| {
         simulate_audio_pipeline(
             Arc::clone(&computer),
             SIMULATED_DELAY_SAMPLES,
             SIMULATED_GAIN,
             SIMULATED_SNR,
+            rng,
         )
     });
 
@@ -68,26 +527,319 @@ fn main() -> Result<()> {
     let _streams = if let Command::Run {
         input_device,
         output_device,
+        microphone_channel,
+        loopback_channel,
+        compensate_drift,
+        ..
     } = args.command
     {
-        Some(run_real_world_audio(
+        let sample_rate_ratio = compensate_drift.then(SampleRateRatio::new);
+        let streams = run_real_world_audio(
             Arc::clone(&computer),
             input_device,
             output_device,
-        )?)
+            InputChannels {
+                microphone: microphone_channel,
+                loopback: loopback_channel,
+            },
+            sample_rate_ratio.clone(),
+            status.clone(),
+        )?;
+        if let Some(calibration) = calibration {
+            calibration.check_sample_rate(computer.read().unwrap().sample_rate_hz())?;
+        }
+        if let Some(ratio) = sample_rate_ratio {
+            compensate_clock_drift(
+                service.subscribe(),
+                ratio,
+                args.minimum_confidence,
+                status.clone(),
+            );
+        }
+        Some(streams)
     } else {
         None
     };
 
-    if args.run_gui {
-        let c = Arc::clone(&computer);
-        thread::spawn(|| {
-            run_tui(c);
-        });
+    // Records written to the standard output replace the TUI.
+    let tui_analyses =
+        (args.export.is_none() || args.export_file.is_some()).then(|| service.subscribe());
+    let exporter = match args.export {
+        Some(format) => Some((
+            service.subscribe(),
+            RecordWriter::create(format, args.export_file.as_deref())?,
+        )),
+        None => None,
+    };
+    let gui_analyses = args.run_gui.then(|| service.subscribe());
+    if let Some((computer, recording, hop_samples, speed)) = replay {
+        // Start only once subscribed, not to miss the first analyses. The service goes away with
+        // the replay, which ends the export and marks the TUI finished.
+        replay_recording(
+            computer,
+            recording,
+            hop_samples,
+            speed,
+            setup,
+            service,
+            status.clone(),
+        );
+    }
+
+    let minimum_confidence = args.minimum_confidence;
+    let exporter = exporter.map(|(analyses, writer)| {
+        let status = status.clone();
+        thread::spawn(move || export_measurements(analyses, writer, minimum_confidence, status))
+    });
+
+    let histogram_bucket_samples = args.histogram_bucket_samples;
+    if let Some(gui_analyses) = gui_analyses {
+        if let Some(tui_analyses) = tui_analyses {
+            let simulator = simulator.clone();
+            thread::spawn(move || {
+                if let Err(err) = run_tui(
+                    tui_analyses,
+                    minimum_confidence,
+                    TrackerConfig::default(),
+                    histogram_bucket_samples,
+                    simulator,
+                    status,
+                ) {
+                    eprintln!("TUI failed: {err:?}");
+                }
+            });
+        }
 
         // Gui must run on the main thread.
-        run_gui(computer, simulator)
-    } else {
-        run_tui(computer)
+        let result = run_gui(gui_analyses, simulator);
+        finish_recording(recorder);
+        return result;
+    }
+
+    let result = match (tui_analyses, exporter) {
+        // Quitting the TUI quits the program, even if the export isn't done yet.
+        (Some(tui_analyses), _) => run_tui(
+            tui_analyses,
+            minimum_confidence,
+            TrackerConfig::default(),
+            histogram_bucket_samples,
+            simulator,
+            status,
+        ),
+        (None, Some(exporter)) => {
+            exporter.join().expect("exporter doesn't panic");
+            Ok(())
+        }
+        (None, None) => Ok(()),
+    };
+    finish_recording(recorder);
+    result
+}
+
+/// Finish the recording, if any. The computer fed by the audio keeps running until the program
+/// exits, so dropping its recorder would never finish the files.
+fn finish_recording(recorder: Option<SessionRecorder>) {
+    if let Some(recorder) = recorder {
+        recorder.finish();
+    }
+}
+
+/// Describe the session for the metadata of its recording.
+fn session_metadata(args: &Args, seed: u64, setup: Setup) -> SessionMetadata {
+    SessionMetadata {
+        // The recorder fills in the actual sample rate and start, the audio the opened devices.
+        sample_rate_hz: DEFAULT_SAMPLE_RATE_HZ,
+        started: SystemTime::now(),
+        input_device: None,
+        output_device: None,
+        seed: Some(seed),
+        parameters: serde_json::to_value(Parameters::new(args, setup))
+            .expect("parameters serialize to JSON"),
     }
 }
+
+/// Measure delays in recorded files and write them in the export format.
+fn run_analysis(args: Args) -> Result<()> {
+    let Command::Analyze {
+        recorded,
+        reference,
+        output,
+        hop_samples,
+    } = &args.command
+    else {
+        unreachable!("called only for the analyze command");
+    };
+
+    let recording = match reference {
+        Some(reference) => Recording::from_files(reference, recorded)?,
+        None => Recording::from_stereo_file(recorded)?,
+    };
+    let setup = args.setup(None);
+    // The excitation isn't played, its seed doesn't matter.
+    let mut computer = build_computer(&args, &mut ChaCha8Rng::from_entropy())?;
+    let hop_samples = hop_samples.unwrap_or(computer.input_buffer().capacity());
+
+    let mut writer =
+        RecordWriter::create(args.export.unwrap_or(ExportFormat::Csv), output.as_deref())?;
+    let mut write_result = Ok(());
+    analyze(
+        &mut computer,
+        &recording,
+        hop_samples,
+        |_| {},
+        |computer, result| {
+            if write_result.is_err() || !result.is_confident(args.minimum_confidence) {
+                return;
+            }
+
+            let measurement = computer.measurement(&result, setup);
+            write_result = writer.write(&Record::new(computer, &result, &measurement));
+        },
+    );
+
+    write_result
+}
+
+/// Measure in both directions along the path and report wind speed.
+fn run_bidirectional(args: Args, status: Status) -> Result<()> {
+    if !matches!(
+        args.excitation,
+        ExcitationKind::WhiteNoise | ExcitationKind::BandLimitedNoise
+    ) {
+        bail!("bidirectional mode needs mutually uncorrelated signals, use a noise excitation");
+    }
+    if args.run_gui {
+        bail!("GUI doesn't support bidirectional mode yet");
+    }
+    if args.record_directory().is_some() {
+        bail!("recording in bidirectional mode isn't supported");
+    }
+
+    // Each computer draws its own noise so that the two directions don't correlate.
+    let mut rng = ChaCha8Rng::seed_from_u64(args.seed());
+    let calibration = args.calibration()?;
+    let computer = Arc::new(RwLock::new(BidirectionalComputer::new(
+        build_computer(&args, &mut rng)?,
+        build_computer(&args, &mut rng)?,
+        args.setup(calibration.as_ref()),
+    )));
+
+    // Keep the simulators and the streams alive and running.
+    let (_simulators, _streams) = match args.command {
+        Command::Simulate { .. } => (
+            Some(simulate_bidirectional_audio_pipeline(
+                Arc::clone(&computer),
+                SIMULATED_DELAY_SAMPLES,
+                SIMULATED_BACKWARD_DELAY_SAMPLES,
+                SIMULATED_GAIN,
+                SIMULATED_SNR,
+                rng,
+            )),
+            None,
+        ),
+        Command::Run {
+            input_device,
+            output_device,
+            ..
+        } => (
+            None,
+            Some(run_real_world_bidirectional_audio(
+                Arc::clone(&computer),
+                input_device,
+                output_device,
+                status.clone(),
+            )?),
+        ),
+        Command::Calibrate { .. } => bail!("calibration in bidirectional mode isn't supported"),
+        Command::Analyze { .. } => bail!("analysis in bidirectional mode isn't supported"),
+        Command::Replay { .. } => bail!("replay in bidirectional mode isn't supported"),
+    };
+    if let Some(calibration) = calibration {
+        calibration.check_sample_rate(computer.read().unwrap().forward().sample_rate_hz())?;
+    }
+
+    let service = MeasurementService::spawn_bidirectional(computer);
+    let Some(format) = args.export else {
+        return run_bidirectional_tui(service.subscribe(), args.minimum_confidence, status);
+    };
+    let writer = RecordWriter::create(format, args.export_file.as_deref())?;
+    if args.export_file.is_none() {
+        // The records take over the standard output.
+        export_bidirectional_measurements(
+            service.subscribe(),
+            writer,
+            args.minimum_confidence,
+            status,
+        );
+        return Ok(());
+    }
+
+    let exported_analyses = service.subscribe();
+    let minimum_confidence = args.minimum_confidence;
+    let exporter_status = status.clone();
+    thread::spawn(move || {
+        export_bidirectional_measurements(
+            exported_analyses,
+            writer,
+            minimum_confidence,
+            exporter_status,
+        );
+    });
+    run_bidirectional_tui(service.subscribe(), args.minimum_confidence, status)
+}
+
+/// Build the computer configured by the arguments. Noise excitations are seeded from `rng`.
+/// Excitations are built for the default sample rate, the computer adapts them to the actual one.
+fn build_computer(args: &Args, rng: &mut ChaCha8Rng) -> Result<Computer> {
+    let excitation_rng = ChaCha8Rng::seed_from_u64(rng.gen());
+    let computer = Computer::new(MAX_EXPECTED_DELAY_SAMPLES, COMPARISON_WINDOW_WIDTH);
+    let computer = match args.excitation {
+        ExcitationKind::WhiteNoise => {
+            computer.with_excitation(WhiteNoise::new().with_rng(excitation_rng))
+        }
+        ExcitationKind::BandLimitedNoise => computer.with_excitation(
+            BandLimitedNoise::new(
+                EXCITATION_LOW_FREQUENCY_HZ,
+                EXCITATION_HIGH_FREQUENCY_HZ,
+                DEFAULT_SAMPLE_RATE_HZ,
+            )
+            .with_rng(excitation_rng),
+        ),
+        ExcitationKind::Mls => {
+            if !MaximumLengthSequence::ORDERS.contains(&args.mls_order) {
+                bail!(
+                    "MLS order must be within {:?}",
+                    MaximumLengthSequence::ORDERS
+                );
+            }
+            // MLS uses its own buffer sizes, see Computer::new_mls().
+            Computer::new_mls(args.mls_order)
+        }
+        // Golay pair uses its own buffer sizes too, see Computer::new_golay().
+        ExcitationKind::Golay => Computer::new_golay(GOLAY_ORDER),
+        ExcitationKind::LinearChirp | ExcitationKind::ExponentialChirp => {
+            let sweep = match args.excitation {
+                ExcitationKind::LinearChirp => Sweep::Linear,
+                _ => Sweep::Exponential,
+            };
+            computer.with_excitation(Chirp::new(
+                EXCITATION_LOW_FREQUENCY_HZ,
+                EXCITATION_HIGH_FREQUENCY_HZ,
+                sweep,
+                CHIRP_DURATION_SAMPLES,
+                DEFAULT_SAMPLE_RATE_HZ,
+            ))
+        }
+        ExcitationKind::MultiTone => computer.with_excitation(MultiTone::new(
+            &MULTI_TONE_FREQUENCIES_HZ,
+            DEFAULT_SAMPLE_RATE_HZ,
+        )),
+    };
+
+    let computer = computer.with_weighting(args.weighting);
+
+    Ok(match args.tracking_window {
+        Some(half_width) => computer.with_tracking_search(half_width, args.minimum_confidence),
+        None => computer,
+    })
+}
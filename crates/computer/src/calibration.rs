@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    path::Path,
+    sync::{mpsc::Receiver, Arc},
+    time::{Duration, Instant},
+};
+
+use eyre::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    service::Analysis,
+    units::{speed_of_sound_from_temperature_m_s, Setup},
+};
+
+/// System latency measured under known conditions. Stored as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Mean of the measured delay minus the expected time of flight and minus the stream latency
+    /// (see [crate::computer::Computer::stream_latency_s]), which is measured anew on every run.
+    pub system_latency_s: f64,
+    /// Standard deviation of the system latency over the calibration period.
+    pub system_latency_std_s: f64,
+    /// Number of measurements the latency was computed from.
+    pub measurements: usize,
+    pub sample_rate_hz: f64,
+    /// Path length and temperature the calibration assumed.
+    pub path_length_m: Option<f64>,
+    pub temperature_c: f64,
+}
+
+impl Calibration {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("reading calibration file {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("parsing calibration file {}", path.display()))
+    }
+
+    /// Fail unless the calibration was done at given sample rate. Most of the latency is buffers
+    /// of a fixed number of frames, which take a different time at another rate.
+    pub fn check_sample_rate(&self, sample_rate_hz: f64) -> Result<()> {
+        if self.sample_rate_hz != sample_rate_hz {
+            bail!(
+                "calibrated at {} Hz but the devices run at {sample_rate_hz} Hz, calibrate again",
+                self.sample_rate_hz
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self).wrap_err("serializing calibration")?;
+        fs::write(path, contents)
+            .wrap_err_with(|| format!("writing calibration file {}", path.display()))
+    }
+}
+
+/// Collect delay measurements for given duration and compute the system latency from them.
+///
+/// Expects still air at `temperature_c`. When the path length is unknown the speaker and the
+/// microphone are assumed to touch, i.e. the whole delay is considered system latency.
+/// Results with confidence below `minimum_confidence` are left out.
+pub fn calibrate(
+    analyses: Receiver<Arc<Analysis>>,
+    duration: Duration,
+    path_length_m: Option<f64>,
+    temperature_c: f64,
+    minimum_confidence: f64,
+) -> Result<Calibration> {
+    let expected_time_of_flight_s =
+        path_length_m.unwrap_or(0.0) / speed_of_sound_from_temperature_m_s(temperature_c);
+
+    let mut latencies = Vec::new();
+    let mut sample_rate_hz = 0.0;
+    let start = Instant::now();
+    while let Some(remaining) = duration.checked_sub(start.elapsed()) {
+        let Ok(analysis) = analyses.recv_timeout(remaining) else {
+            break;
+        };
+
+        let Analysis {
+            computer, result, ..
+        } = analysis.as_ref();
+        if result.is_confident(minimum_confidence) {
+            // Measure the delay without any previous calibration applied. The time of flight is
+            // then what's left after the stream latency, which later runs subtract on their own.
+            let measurement = computer.measurement(result, Setup::default());
+            latencies.push(measurement.time_of_flight_s() - expected_time_of_flight_s);
+            sample_rate_hz = computer.sample_rate_hz();
+        }
+    }
+
+    if latencies.is_empty() {
+        bail!("no confident measurement during calibration, check the speaker and the microphone");
+    }
+
+    let count = latencies.len() as f64;
+    let mean = latencies.iter().sum::<f64>() / count;
+    let variance = latencies
+        .iter()
+        .map(|latency| (latency - mean).powi(2))
+        .sum::<f64>()
+        / count;
+
+    Ok(Calibration {
+        system_latency_s: mean,
+        system_latency_std_s: variance.sqrt(),
+        measurements: latencies.len(),
+        sample_rate_hz,
+        path_length_m,
+        temperature_c,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{computer::Computer, Sample};
+
+    const SAMPLE_RATE_HZ: f64 = 48_000.0;
+    const TEMPERATURE_C: f64 = 20.0;
+
+    /// Analysis of a computer whose input lags the output by `delay_samples`, of which
+    /// `stream_latency_samples` is known from the stream timestamps.
+    fn analysis(delay_samples: usize, stream_latency_samples: f64, setup: Setup) -> Arc<Analysis> {
+        let mut rng = ChaCha8Rng::seed_from_u64(3);
+        let output: Vec<Sample> = (0..4096).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| {
+                index
+                    .checked_sub(delay_samples)
+                    .map_or(0.0, |index| output[index])
+            })
+            .collect();
+
+        let mut computer = Computer::new(256, 1024);
+        computer.set_sample_rate_hz(SAMPLE_RATE_HZ);
+        computer.tag_output_buffer(stream_latency_samples / SAMPLE_RATE_HZ);
+        computer.tag_input_buffer(0.0);
+        computer.record_output_samples(&output);
+        computer.record_samples(&input);
+
+        let result = computer.delay().unwrap();
+        Arc::new(Analysis {
+            measurement: computer.measurement(&result, setup),
+            computer,
+            result,
+        })
+    }
+
+    #[test]
+    fn calibrated_measurement_gives_expected_time_of_flight() {
+        let time_of_flight_samples = 60.0;
+        let path_length_m = time_of_flight_samples / SAMPLE_RATE_HZ
+            * speed_of_sound_from_temperature_m_s(TEMPERATURE_C);
+        let stream_latency_samples = 40.0;
+        // 30 samples of latency the stream timestamps don't account for.
+        let delay_samples = 130;
+
+        let (sender, receiver) = mpsc::sync_channel(1);
+        sender
+            .send(analysis(
+                delay_samples,
+                stream_latency_samples,
+                Setup::default(),
+            ))
+            .unwrap();
+        drop(sender);
+        let calibration = calibrate(
+            receiver,
+            Duration::from_secs(1),
+            Some(path_length_m),
+            TEMPERATURE_C,
+            0.5,
+        )
+        .unwrap();
+        assert!((calibration.system_latency_s * SAMPLE_RATE_HZ - 30.0).abs() < 0.05);
+
+        let setup = Setup {
+            path_length_m: Some(path_length_m),
+            system_latency_s: calibration.system_latency_s,
+        };
+        let measurement = analysis(delay_samples, stream_latency_samples, setup).measurement;
+        assert!(
+            (measurement.time_of_flight_s() * SAMPLE_RATE_HZ - time_of_flight_samples).abs() < 0.05
+        );
+        assert!((measurement.temperature_c().unwrap() - TEMPERATURE_C).abs() < 0.5);
+    }
+
+    #[test]
+    fn calibration_applies_only_at_its_sample_rate() {
+        let calibration = Calibration {
+            system_latency_s: 0.01,
+            system_latency_std_s: 0.0,
+            measurements: 1,
+            sample_rate_hz: SAMPLE_RATE_HZ,
+            path_length_m: None,
+            temperature_c: TEMPERATURE_C,
+        };
+
+        assert!(calibration.check_sample_rate(SAMPLE_RATE_HZ).is_ok());
+        assert!(calibration.check_sample_rate(44_100.0).is_err());
+    }
+
+    #[test]
+    fn calibration_fails_without_confident_measurement() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        drop(sender);
+
+        assert!(calibrate(receiver, Duration::from_secs(1), None, TEMPERATURE_C, 0.5).is_err());
+    }
+}
@@ -1,81 +1,625 @@
 use core::f32;
+use std::{
+    ops::Range,
+    time::{Instant, SystemTime},
+};
 
-use rand::distributions::Distribution;
-use rand::thread_rng;
-use statrs::distribution::Normal;
+use crate::{
+    correlation::{
+        parabolic_peak_offset, peak_to_sidelobe_ratio, second_peak_distance, Correlator, Weighting,
+    },
+    excitation::{Excitation, GolayPair, MaximumLengthSequence, WhiteNoise},
+    recording::{SessionRecorder, Track},
+    ring_buffer::RingBuffer,
+    timing::StreamClock,
+    units::{Measurement, Setup},
+    Sample,
+};
 
-use crate::{ring_buffer::RingBuffer, Sample};
+/// Sample rate assumed until the actual one is known, e.g. from the configuration of audio devices.
+pub const DEFAULT_SAMPLE_RATE_HZ: f64 = 48_000.0;
 
 #[derive(Debug, Clone)]
 pub struct Computer {
     output: RingBuffer<Sample>,
     input: RingBuffer<Sample>,
+    /// Input from an electrical loopback of the output, if any. Has the same latency as the
+    /// acoustic input, but no time of flight.
+    loopback: Option<RingBuffer<Sample>>,
+    correlator: Correlator,
+    excitation: Box<dyn Excitation>,
+    /// Whether the excitation is periodic and both buffers hold exactly one period of it.
+    /// The delay is then found by circular correlation.
+    circular: bool,
+    /// One period of the Golay pair the computer plays, if it does. See [Computer::new_golay].
+    golay_period: Option<Vec<Sample>>,
+    /// Sample rate of both the output and the input. Used only to convert delays to seconds.
+    sample_rate_hz: f64,
+    tracking: Option<TrackingSearch>,
+    /// Number of samples played and recorded so far.
+    output_samples: u64,
+    input_samples: u64,
+    /// Timing of the streams, known only when the audio callbacks provide timestamps.
+    output_clock: StreamClock,
+    input_clock: StreamClock,
+    /// Shared between clones of the computer, though only the one fed with samples writes to it.
+    recorder: Option<SessionRecorder>,
+}
+
+/// Configuration of the tracking search. See [Computer::with_tracking_search].
+#[derive(Debug, Clone, Copy)]
+struct TrackingSearch {
+    half_width: usize,
+    minimum_confidence: f64,
+}
+
+/// Delay the tracking search (see [Computer::with_tracking_search]) is locked on, if any.
+/// Kept by whoever computes successive delays rather than by the computer, as delays are usually
+/// computed on short-lived snapshots of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchLock {
+    delay_samples: Option<usize>,
+}
+
+impl SearchLock {
+    pub fn delay_samples(&self) -> Option<usize> {
+        self.delay_samples
+    }
 }
 
 impl Computer {
     pub fn new(maximum_expected_delay_samples: usize, comparison_window_width: usize) -> Self {
+        let output_capacity = maximum_expected_delay_samples + comparison_window_width;
+
         Self {
-            output: RingBuffer::new(maximum_expected_delay_samples + comparison_window_width),
+            output: RingBuffer::new(output_capacity),
             input: RingBuffer::new(comparison_window_width),
+            loopback: None,
+            correlator: Correlator::new(
+                output_capacity,
+                comparison_window_width,
+                Weighting::default(),
+            ),
+            excitation: Box::new(WhiteNoise::new()),
+            circular: false,
+            golay_period: None,
+            sample_rate_hz: DEFAULT_SAMPLE_RATE_HZ,
+            tracking: None,
+            output_samples: 0,
+            input_samples: 0,
+            output_clock: StreamClock::default(),
+            input_clock: StreamClock::default(),
+            recorder: None,
+        }
+    }
+
+    /// Construct a computer playing a periodic maximum-length sequence of given order.
+    /// Both the comparison window and the maximum expected delay are one period of the sequence
+    /// (`2^order - 1` samples) and the delay is found by circular correlation, which gives MLS
+    /// its ideal autocorrelation.
+    pub fn new_mls(order: u32) -> Self {
+        let excitation = MaximumLengthSequence::new(order);
+        let period = excitation.period();
+
+        Self {
+            output: RingBuffer::new(period),
+            input: RingBuffer::new(period),
+            loopback: None,
+            correlator: Correlator::circular(period, Weighting::default()),
+            excitation: Box::new(excitation),
+            circular: true,
+            golay_period: None,
+            sample_rate_hz: DEFAULT_SAMPLE_RATE_HZ,
+            tracking: None,
+            output_samples: 0,
+            input_samples: 0,
+            output_clock: StreamClock::default(),
+            input_clock: StreamClock::default(),
+            recorder: None,
+        }
+    }
+
+    /// Construct a computer playing a Golay complementary pair with sequences of `2^order` samples.
+    /// Both buffers hold one period of the pair (see [GolayPair]). Each sequence is correlated
+    /// circularly with the input that arrived while it played and the two correlations are
+    /// summed, which cancels their sidelobes. Delays are measured modulo the sequence length.
+    pub fn new_golay(order: u32) -> Self {
+        let excitation = GolayPair::new(order);
+        let period = excitation.period().len();
+
+        Self {
+            output: RingBuffer::new(period),
+            input: RingBuffer::new(period),
+            loopback: None,
+            correlator: Correlator::circular(excitation.sequence_length(), Weighting::default()),
+            golay_period: Some(excitation.period().to_vec()),
+            excitation: Box::new(excitation),
+            circular: true,
+            sample_rate_hz: DEFAULT_SAMPLE_RATE_HZ,
+            tracking: None,
+            output_samples: 0,
+            input_samples: 0,
+            output_clock: StreamClock::default(),
+            input_clock: StreamClock::default(),
+            recorder: None,
         }
     }
 
+    /// Play given excitation signal instead of the default white noise. Panic on computers
+    /// constructed by [Computer::new_mls] or [Computer::new_golay], whose buffers fit their signal.
+    pub fn with_excitation(mut self, excitation: impl Excitation + 'static) -> Self {
+        assert!(
+            !self.circular,
+            "the excitation of a circular correlation computer can't be replaced"
+        );
+        self.excitation = Box::new(excitation);
+        self
+    }
+
+    /// Use given generalized cross-correlation weighting when computing the delay.
+    pub fn with_weighting(mut self, weighting: Weighting) -> Self {
+        self.correlator.set_weighting(weighting);
+        self
+    }
+
+    /// Expect samples of an electrical loopback of the output to be recorded by
+    /// [Computer::record_loopback_sample]. The delay is then also measured on the loopback, which
+    /// replaces the configured system latency.
+    pub fn with_loopback(mut self) -> Self {
+        self.loopback = Some(RingBuffer::new(self.input.capacity()));
+        self
+    }
+
+    /// Once a delay with at least `minimum_confidence` is found, search only delays within
+    /// `half_width` samples of the last one. This keeps spurious far-off peaks from winning and is
+    /// cheaper, mostly so without weighting, when every searched delay takes just a dot product.
+    /// Fall back to the full search when the confidence drops or the peak hits the edge of the
+    /// search window. See [Computer::tracking_delay].
+    pub fn with_tracking_search(mut self, half_width: usize, minimum_confidence: f64) -> Self {
+        self.tracking = Some(TrackingSearch {
+            half_width,
+            minimum_confidence,
+        });
+        self
+    }
+
+    /// Write all played and recorded samples to WAV files.
+    pub fn with_recorder(mut self, recorder: SessionRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    pub fn recorder(&self) -> Option<&SessionRecorder> {
+        self.recorder.as_ref()
+    }
+
+    pub fn weighting(&self) -> Weighting {
+        self.correlator.weighting()
+    }
+
+    pub fn sample_rate_hz(&self) -> f64 {
+        self.sample_rate_hz
+    }
+
+    /// Set the sample rate of the output and the input, e.g. once negotiated with the audio
+    /// devices. The excitation adapts to it too.
+    pub fn set_sample_rate_hz(&mut self, sample_rate_hz: f64) {
+        assert!(sample_rate_hz > 0.0, "sample rate must be positive");
+        self.sample_rate_hz = sample_rate_hz;
+        self.excitation.set_sample_rate_hz(sample_rate_hz);
+    }
+
+    /// Convert the delay result to physical units. When the result comes with a loopback delay,
+    /// it is used as the system latency instead of the one in the setup. Otherwise the stream
+    /// latency, if known, is added to the one in the setup.
+    pub fn measurement(&self, result: &DelayResult, setup: Setup) -> Measurement {
+        let setup = match (result.loopback_delay_samples, result.stream_latency_samples) {
+            (Some(loopback_delay_samples), _) => Setup {
+                system_latency_s: loopback_delay_samples / self.sample_rate_hz,
+                ..setup
+            },
+            (None, Some(stream_latency_samples)) => Setup {
+                system_latency_s: setup.system_latency_s
+                    + stream_latency_samples / self.sample_rate_hz,
+                ..setup
+            },
+            (None, None) => setup,
+        };
+
+        Measurement::new(result.precise_delay_samples, self.sample_rate_hz, setup)
+    }
+
     /// Return the next audio sample in cpal's F32 format.
     pub fn output_sample(&mut self) -> Sample {
-        // Generate random noise with normal distribution to approximate real-world noise.
-        // Set standard deviation to 0.5 to utilize entire cpal range (-1, 1) without excessive clipping.
-        // With STD of 0.5 about 5% of samples end up outside the range and are clamped below.
-        let distribution = Normal::new(0.0, 0.5).expect("mean and standard deviation are sane");
-        let sample = distribution.sample(&mut thread_rng()).clamp(-1.0, 1.0) as f32;
+        let sample = self.excitation.next_sample();
 
-        self.output.push_back(sample);
+        self.record_output_sample(sample);
         sample
     }
 
+    /// Fill the slice with next audio samples. Like [Computer::output_sample], but for many
+    /// samples at once.
+    pub fn fill_output(&mut self, samples: &mut [Sample]) {
+        samples
+            .iter_mut()
+            .for_each(|sample| *sample = self.excitation.next_sample());
+
+        self.record_output_samples(samples);
+    }
+
+    /// Copy of the excitation in its current state. Lets the audio thread generate the output on
+    /// its own and hand the samples over by [Computer::record_output_sample].
+    pub fn excitation(&self) -> Box<dyn Excitation> {
+        self.excitation.clone()
+    }
+
+    /// Record a played sample generated outside of the computer.
+    pub fn record_output_sample(&mut self, sample: Sample) {
+        self.output.push_back(sample);
+        self.output_samples += 1;
+        self.record_track(Track::Output, &[sample]);
+    }
+
+    /// Record played samples generated outside of the computer.
+    pub fn record_output_samples(&mut self, samples: &[Sample]) {
+        self.output.extend_from_slice(samples);
+        self.output_samples += samples.len() as u64;
+        self.record_track(Track::Output, samples);
+    }
+
     pub fn record_sample(&mut self, sample: Sample) {
         self.input.push_back(sample);
+        self.input_samples += 1;
+        self.record_track(Track::Input, &[sample]);
+    }
+
+    pub fn record_samples(&mut self, samples: &[Sample]) {
+        self.input.extend_from_slice(samples);
+        self.input_samples += samples.len() as u64;
+        self.record_track(Track::Input, samples);
     }
 
+    /// Note the instant (see [StreamClock]) at which the next output sample will be played.
+    /// Call before generating each output buffer.
+    pub fn tag_output_buffer(&mut self, playback_instant_s: f64) {
+        self.output_clock
+            .tag(self.output_samples, playback_instant_s, self.sample_rate_hz);
+    }
+
+    /// Note the instant (see [StreamClock]) at which the next input sample was captured.
+    /// Call before recording each input buffer.
+    pub fn tag_input_buffer(&mut self, capture_instant_s: f64) {
+        self.input_clock
+            .tag(self.input_samples, capture_instant_s, self.sample_rate_hz);
+    }
+
+    /// Time between capturing the most recent input sample and playing the most recent output
+    /// sample. This is the part of the delay caused by buffering and callback scheduling rather
+    /// than by the sound travelling. None unless both streams are tagged with timestamps.
+    pub fn stream_latency_s(&self) -> Option<f64> {
+        let last_played_s = self
+            .output_clock
+            .instant_s(self.output_samples.checked_sub(1)?, self.sample_rate_hz)?;
+        let last_captured_s = self
+            .input_clock
+            .instant_s(self.input_samples.checked_sub(1)?, self.sample_rate_hz)?;
+
+        Some(last_played_s - last_captured_s)
+    }
+
+    /// Record a sample of the electrical loopback. Ignored unless constructed
+    /// [Computer::with_loopback].
+    pub fn record_loopback_sample(&mut self, sample: Sample) {
+        if let Some(loopback) = self.loopback.as_mut() {
+            loopback.push_back(sample);
+            self.record_track(Track::Loopback, &[sample]);
+        }
+    }
+
+    /// Record samples of the electrical loopback, see [Computer::record_loopback_sample].
+    pub fn record_loopback_samples(&mut self, samples: &[Sample]) {
+        if let Some(loopback) = self.loopback.as_mut() {
+            loopback.extend_from_slice(samples);
+            self.record_track(Track::Loopback, samples);
+        }
+    }
+
+    fn record_track(&self, track: Track, samples: &[Sample]) {
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(track, samples, self.sample_rate_hz);
+        }
+    }
+
+    /// Find the delay, searching all delays the computer can measure.
     pub fn delay(&self) -> Option<DelayResult> {
-        if !self.input.is_full() {
+        self.tracking_delay(&mut SearchLock::default())
+    }
+
+    /// Find the delay by the tracking search around the delay the lock holds and update the lock
+    /// by the result. Keep the lock for the next delay of this computer or its clones.
+    /// Same as [Computer::delay] unless the computer is constructed
+    /// [Computer::with_tracking_search].
+    pub fn tracking_delay(&self, lock: &mut SearchLock) -> Option<DelayResult> {
+        let instant = Instant::now();
+        let wall_clock = SystemTime::now();
+
+        let peak = match self.tracking {
+            Some(tracking) => self.tracked_peak(tracking, lock)?,
+            None => self.peak_of(&self.input, None)?,
+        };
+        let loopback_delay_samples = match self.loopback.as_ref() {
+            Some(loopback) => Some(self.peak_of(loopback, None)?.precise_delay_samples),
+            None => None,
+        };
+
+        Some(DelayResult {
+            delay_samples: peak.delay_samples,
+            precise_delay_samples: peak.precise_delay_samples,
+            loopback_delay_samples,
+            stream_latency_samples: self
+                .stream_latency_s()
+                .map(|latency_s| latency_s * self.sample_rate_hz),
+            output_samples: self.output_samples - self.output.len() as u64..self.output_samples,
+            input_samples: self.input_samples - self.input.len() as u64..self.input_samples,
+            instant,
+            wall_clock,
+            quality: peak.quality,
+            cross_correlation: peak.cross_correlation,
+        })
+    }
+
+    /// Search around the locked delay, if any, and update the lock according to the result.
+    fn tracked_peak(&self, tracking: TrackingSearch, lock: &mut SearchLock) -> Option<Peak> {
+        if let Some(center) = lock.delay_samples {
+            let window = SearchWindow {
+                center,
+                half_width: tracking.half_width,
+            };
+            let (_, maximum_shift) = self.shift_range(&self.input);
+
+            if let Some(peak) = self.peak_of(&self.input, Some(window)).filter(|peak| {
+                peak.quality.confidence() >= tracking.minimum_confidence
+                    && !window.is_at_edge(peak.delay_samples, maximum_shift, self.circular)
+            }) {
+                lock.delay_samples = Some(peak.delay_samples);
+                return Some(peak);
+            }
+        }
+
+        // Not locked yet or just lost the lock. Search the full range.
+        let peak = self.peak_of(&self.input, None)?;
+        lock.delay_samples = (peak.quality.confidence() >= tracking.minimum_confidence)
+            .then_some(peak.delay_samples);
+
+        Some(peak)
+    }
+
+    /// Find the correlation peak, i.e. the delay, of given input relative to the output. Consider
+    /// only delays within the search window, if given.
+    fn peak_of(&self, input: &RingBuffer<Sample>, window: Option<SearchWindow>) -> Option<Peak> {
+        if !input.is_full() {
             // We haven't yet accumulated enough input samples. We'll need to wait bit more.
             return None;
         }
 
-        // +1 needs to be there to cover 0 delay.
-        let maximum_shift = self.output.len().saturating_sub(self.input.len()) + 1;
+        if self.circular && !self.output.is_full() {
+            // Circular correlation needs an entire period of the output.
+            return None;
+        }
 
-        // Find the phase shift that produced the maximum correlation.
-        // TODO: make this code nicer. Unfortunately f32 isn't Ord so we can't use Iterator::min().
-        let mut max_correlation = f32::MIN;
-        let mut corresponding_phase_shift = 0;
-        let mut cross_correlation = Vec::new();
-
-        for phase_shift_samples in 0..maximum_shift {
-            let output_window = self.output.iter().skip(phase_shift_samples);
-            let input_window = self.input.iter();
-
-            let correlation = output_window
-                .zip(input_window)
-                .fold(0.0, |acc, (output_sample, input_sample)| {
-                    acc + (output_sample * input_sample)
-                });
-
-            cross_correlation.push(correlation);
-
-            if correlation > max_correlation {
-                max_correlation = correlation;
-                corresponding_phase_shift = phase_shift_samples;
-            }
+        let segments = self.segments(input)?;
+        let (zero_delay_shift, maximum_shift) = self.shift_range(input);
+
+        // Phase shifts to evaluate, each next to the previous one. With circular correlation they
+        // wrap around the period, so they don't always increase. Larger shift means shorter delay,
+        // so the window's delays come reversed.
+        let shifts: Vec<usize> = match window {
+            None => (0..maximum_shift).collect(),
+            Some(window) => window
+                .delays(maximum_shift, self.circular)
+                .rev()
+                .map(|delay| (zero_delay_shift + maximum_shift - delay) % maximum_shift)
+                .collect(),
+        };
+        let cross_correlation = match window {
+            None => summed(segments.iter().map(|segment| {
+                self.correlator
+                    .cross_correlate(&segment.output, &segment.input, maximum_shift)
+            })),
+            Some(_) => self.windowed_cross_correlation(&segments, &shifts),
+        };
+
+        if !cross_correlation.iter().all(|value| value.is_finite()) {
+            // E.g. the input is full of infinities. There's no peak to find.
+            return None;
         }
 
-        Some(DelayResult {
-            // Subtract the +1 we added to maximum_shift above.
-            delay_samples: maximum_shift - corresponding_phase_shift - 1,
+        // Find the phase shift that produced the maximum correlation.
+        // f32 isn't Ord so we can't use Iterator::max().
+        let (peak, _) = cross_correlation.iter().enumerate().fold(
+            (0, f32::MIN),
+            |(best_index, best_correlation), (index, &correlation)| {
+                if correlation > best_correlation {
+                    (index, correlation)
+                } else {
+                    (best_index, best_correlation)
+                }
+            },
+        );
+        let corresponding_phase_shift = *shifts.get(peak)?;
+
+        // A full circular correlation wraps around, a peak at its seam still has both neighbours.
+        let precise_phase_shift = corresponding_phase_shift as f64
+            + parabolic_peak_offset(&cross_correlation, peak, self.circular && window.is_none());
+
+        let quality = self.quality(
+            &segments,
+            &cross_correlation,
+            corresponding_phase_shift,
+            peak,
+        );
+
+        // With circular correlation, shifts past the zero delay one wrap around to the longest
+        // delays. In the linear case there are no such shifts and the modulo is a no-op.
+        Some(Peak {
+            delay_samples: (zero_delay_shift + maximum_shift - corresponding_phase_shift)
+                % maximum_shift,
+            // Larger phase shift means shorter delay, hence the interpolated offset is subtracted.
+            precise_delay_samples: (zero_delay_shift as f64 - precise_phase_shift)
+                .rem_euclid(maximum_shift as f64),
+            quality,
             cross_correlation,
         })
     }
 
+    /// Parts of the output and of given input to correlate. The whole buffers, unless the
+    /// computer plays a Golay pair.
+    fn segments(&self, input: &RingBuffer<Sample>) -> Option<Vec<Segment>> {
+        let output: Vec<Sample> = self.output.iter().copied().collect();
+        let input: Vec<Sample> = input.iter().copied().collect();
+        let Some(period) = self.golay_period.as_ref() else {
+            return Some(vec![Segment { output, input }]);
+        };
+
+        let phase = self.golay_phase(period)?;
+        let length = period.len() / 4;
+        // The second copy of each sequence. As delays are shorter than a sequence, the input
+        // there comes from the same sequence only, see GolayPair.
+        let segments = [length, 3 * length]
+            .into_iter()
+            .map(|position| {
+                // The buffers are aligned, the newest input with the newest output.
+                let start = (position + period.len() - phase) % period.len();
+                let take = |samples: &[Sample]| {
+                    samples
+                        .iter()
+                        .cycle()
+                        .skip(start)
+                        .take(length)
+                        .copied()
+                        .collect()
+                };
+
+                Segment {
+                    output: take(&output),
+                    input: take(&input),
+                }
+            })
+            .collect();
+
+        Some(segments)
+    }
+
+    /// Position within the Golay pair's period of the oldest output sample. Found by matching the
+    /// output with the period, starting at the position given by the number of samples played.
+    /// None while the output isn't a clean period of the pair, e.g. right after samples got lost.
+    fn golay_phase(&self, period: &[Sample]) -> Option<usize> {
+        let expected = (self.output_samples % period.len() as u64) as usize;
+
+        (0..period.len())
+            .map(|offset| (expected + offset) % period.len())
+            .find(|&phase| {
+                // Mismatching phases mostly fail on the first few samples.
+                self.output
+                    .iter()
+                    .zip(period[phase..].iter().chain(&period[..phase]))
+                    .all(|(played, expected)| played == expected)
+            })
+    }
+
+    /// The phase shift at which the input lines up with the most recent output, i.e. zero delay,
+    /// and the number of phase shifts the computer can evaluate.
+    fn shift_range(&self, input: &RingBuffer<Sample>) -> (usize, usize) {
+        if let Some(period) = self.golay_period.as_ref() {
+            // The correlated segments are single sequences.
+            return (0, period.len() / 4);
+        }
+
+        let zero_delay_shift = self.output.len().saturating_sub(input.len());
+        let maximum_shift = if self.circular {
+            self.output.len()
+        } else {
+            // +1 needs to be there to cover 0 delay.
+            zero_delay_shift + 1
+        };
+
+        (zero_delay_shift, maximum_shift)
+    }
+
+    /// Correlation at given phase shifts only, in their order.
+    fn windowed_cross_correlation(&self, segments: &[Segment], shifts: &[usize]) -> Vec<Sample> {
+        summed(segments.iter().map(|segment| {
+            if self.correlator.weighting() != Weighting::Unweighted {
+                // Weightings work in frequency domain and need spectra of the whole signals. Only
+                // the inverse transform is saved.
+                return self
+                    .correlator
+                    .cross_correlate_at(&segment.output, &segment.input, shifts);
+            }
+
+            // For a narrow window, plain dot products are cheaper than the FFT.
+            shifts
+                .iter()
+                .map(|&shift| {
+                    segment
+                        .output_from(shift)
+                        .zip(&segment.input)
+                        .map(|(output_sample, input_sample)| output_sample * input_sample)
+                        .sum()
+                })
+                .collect()
+        }))
+    }
+
+    /// Quality of the peak at index `peak` of the correlation, which corresponds to `phase_shift`.
+    /// Sidelobes are looked for only among the evaluated shifts.
+    fn quality(
+        &self,
+        segments: &[Segment],
+        cross_correlation: &[Sample],
+        phase_shift: usize,
+        peak: usize,
+    ) -> Quality {
+        // Compute the normalized peak from raw signals so that it doesn't depend on the weighting.
+        let (dot_product, output_energy, input_energy) = segments
+            .iter()
+            .flat_map(|segment| segment.output_from(phase_shift).zip(&segment.input))
+            .fold(
+                (0.0, 0.0, 0.0),
+                |(dot, output_energy, input_energy), (&o, &i)| {
+                    let (o, i) = (o as f64, i as f64);
+                    (dot + o * i, output_energy + o * o, input_energy + i * i)
+                },
+            );
+        let normalization = (output_energy * input_energy).sqrt();
+        let normalized_peak = if normalization > 0.0 {
+            dot_product / normalization
+        } else {
+            0.0
+        };
+
+        // Correlation coefficient of a signal with its noisy copy is sqrt(SNR / (1 + SNR)).
+        let squared_peak = normalized_peak.powi(2).min(1.0 - f64::EPSILON);
+        let snr_db = 10.0 * (squared_peak / (1.0 - squared_peak)).log10();
+
+        Quality {
+            peak_to_sidelobe_ratio: peak_to_sidelobe_ratio(cross_correlation, peak),
+            normalized_peak,
+            snr_db,
+            second_peak_distance: second_peak_distance(cross_correlation, peak),
+        }
+    }
+
+    /// Number of samples played so far. Also the absolute index of the next one.
+    pub fn output_sample_count(&self) -> u64 {
+        self.output_samples
+    }
+
+    /// Number of samples recorded so far. Also the absolute index of the next one.
+    pub fn input_sample_count(&self) -> u64 {
+        self.input_samples
+    }
+
     pub fn input_buffer(&self) -> &RingBuffer<Sample> {
         &self.input
     }
@@ -85,7 +629,454 @@ impl Computer {
     }
 }
 
+/// Correlation peak of an input, the part of [DelayResult] that depends on the searched input.
+#[derive(Debug)]
+struct Peak {
+    delay_samples: usize,
+    precise_delay_samples: f64,
+    quality: Quality,
+    cross_correlation: Vec<Sample>,
+}
+
+/// Output and input correlated with each other, see [Computer::segments].
+#[derive(Debug)]
+struct Segment {
+    output: Vec<Sample>,
+    input: Vec<Sample>,
+}
+
+impl Segment {
+    /// Output samples from given index on, wrapping around to the start when correlating
+    /// circularly. Never wraps in the linear case as the input is shorter.
+    fn output_from(&self, start: usize) -> impl Iterator<Item = &Sample> {
+        self.output[start..].iter().chain(&self.output[..start])
+    }
+}
+
+/// Sum of the correlations of all segments.
+fn summed(correlations: impl Iterator<Item = Vec<Sample>>) -> Vec<Sample> {
+    correlations
+        .reduce(|mut sum, correlation| {
+            sum.iter_mut()
+                .zip(correlation)
+                .for_each(|(sum, value)| *sum += value);
+            sum
+        })
+        .unwrap_or_default()
+}
+
+/// Range of delays around a center, used by the tracking search.
+#[derive(Debug, Clone, Copy)]
+struct SearchWindow {
+    center: usize,
+    half_width: usize,
+}
+
+impl SearchWindow {
+    /// Delays within the window that the computer can measure. With circular correlation delays
+    /// wrap around the period.
+    fn delays(
+        &self,
+        maximum_shift: usize,
+        circular: bool,
+    ) -> impl DoubleEndedIterator<Item = usize> {
+        let center = self.center as isize;
+        let half_width = self.half_width as isize;
+        let period = maximum_shift as isize;
+
+        (center - half_width..=center + half_width).filter_map(move |delay| {
+            if circular {
+                Some(delay.rem_euclid(period) as usize)
+            } else {
+                (0..period).contains(&delay).then_some(delay as usize)
+            }
+        })
+    }
+
+    fn is_at_edge(&self, delay: usize, maximum_shift: usize, circular: bool) -> bool {
+        let distance = delay.abs_diff(self.center);
+        let distance = if circular {
+            distance.min(maximum_shift - distance)
+        } else {
+            distance
+        };
+
+        distance >= self.half_width
+    }
+}
+
+#[derive(Debug)]
 pub struct DelayResult {
     pub delay_samples: usize,
+    /// Delay with sub-sample precision, interpolated around the correlation peak.
+    pub precise_delay_samples: f64,
+    /// Delay of the electrical loopback, if the computer has one.
+    pub loopback_delay_samples: Option<f64>,
+    /// Part of the delay caused by buffering and scheduling of the audio streams, measured from
+    /// their timestamps. See [Computer::stream_latency_s].
+    pub stream_latency_samples: Option<f64>,
+    /// Absolute indices (see [Computer::output_sample_count]) of the output and the input samples
+    /// the result was computed from.
+    pub output_samples: Range<u64>,
+    pub input_samples: Range<u64>,
+    /// When the result was computed. The monotonic instant for measuring intervals, the wall
+    /// clock for aligning with other data.
+    pub instant: Instant,
+    pub wall_clock: SystemTime,
+    pub quality: Quality,
+    /// Correlation at the evaluated phase shifts, each next to the previous one. Covers all shifts
+    /// unless the tracking search narrowed them down to a window around the delay, which wraps
+    /// around the period with circular correlation.
     pub cross_correlation: Vec<Sample>,
 }
+
+impl DelayResult {
+    /// Whether the result is trustworthy enough given the minimum confidence.
+    pub fn is_confident(&self, minimum_confidence: f64) -> bool {
+        self.quality.confidence() >= minimum_confidence
+    }
+
+    /// Delay relative to the electrical loopback, i.e. with the device latency cancelled out.
+    /// Same as the precise delay when there's no loopback.
+    pub fn relative_delay_samples(&self) -> f64 {
+        self.precise_delay_samples - self.loopback_delay_samples.unwrap_or(0.0)
+    }
+}
+
+/// Metrics describing how distinct the correlation peak is and so how much to trust the result.
+#[derive(Debug, Clone, Copy)]
+pub struct Quality {
+    /// Ratio of the correlation peak to the highest sidelobe. Values close to 1 mean ambiguity.
+    pub peak_to_sidelobe_ratio: f64,
+    /// Correlation coefficient (-1..=1) of the input and the output aligned by the found delay.
+    pub normalized_peak: f64,
+    /// Signal to noise ratio (in dB) of the input, estimated from the normalized peak.
+    pub snr_db: f64,
+    /// Distance (in samples) of the second highest correlation peak from the main one.
+    pub second_peak_distance: Option<usize>,
+}
+
+impl Quality {
+    /// Single number (0..=1) summarizing the quality. It is the normalized peak, i.e. how much of
+    /// the input is explained by the delayed output.
+    pub fn confidence(&self) -> f64 {
+        self.normalized_peak.clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    fn noise(length: usize) -> Vec<Sample> {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        (0..length).map(|_| rng.gen_range(-1.0..1.0)).collect()
+    }
+
+    /// Computer fed with noise and its copy passed through the filter, which is applied to the
+    /// noise delayed by `delay` samples.
+    fn computer_with_delayed_input(
+        delay: usize,
+        filter: impl Fn(&[Sample], usize) -> Sample,
+    ) -> Computer {
+        let output = noise(4096);
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| {
+                index
+                    .checked_sub(delay)
+                    .map_or(0.0, |index| filter(&output, index))
+            })
+            .collect();
+
+        let mut computer = Computer::new(256, 1024);
+        computer.record_output_samples(&output);
+        computer.record_samples(&input);
+        computer
+    }
+
+    #[test]
+    fn finds_integer_delay() {
+        let computer = computer_with_delayed_input(37, |output, index| output[index]);
+
+        let result = computer.delay().unwrap();
+        assert_eq!(result.delay_samples, 37);
+        assert!((result.precise_delay_samples - 37.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn finds_fractional_delay() {
+        // Averaging neighbouring samples delays the signal by another half sample.
+        let computer = computer_with_delayed_input(37, |output, index| {
+            0.5 * (output[index] + index.checked_sub(1).map_or(0.0, |index| output[index]))
+        });
+
+        let result = computer.delay().unwrap();
+        assert!(
+            (result.precise_delay_samples - 37.5).abs() < 0.1,
+            "delay {}",
+            result.precise_delay_samples
+        );
+    }
+
+    #[test]
+    fn delay_matches_time_domain_correlation() {
+        let computer = computer_with_delayed_input(37, |output, index| 0.8 * output[index]);
+        let output: Vec<Sample> = computer.output_buffer().iter().copied().collect();
+        let input: Vec<Sample> = computer.input_buffer().iter().copied().collect();
+
+        // The nested loop the computer used to try every phase shift with.
+        let maximum_shift = output.len() - input.len() + 1;
+        let time_domain: Vec<Sample> = (0..maximum_shift)
+            .map(|phase_shift| {
+                output[phase_shift..]
+                    .iter()
+                    .zip(&input)
+                    .map(|(output, input)| output * input)
+                    .sum()
+            })
+            .collect();
+        let peak = time_domain.iter().copied().fold(Sample::MIN, Sample::max);
+        let peak_shift = time_domain.iter().position(|&value| value == peak).unwrap();
+
+        let result = computer.delay().unwrap();
+        assert_eq!(result.delay_samples, maximum_shift - peak_shift - 1);
+        assert_eq!(result.cross_correlation.len(), time_domain.len());
+        for (shift, (fft, time_domain)) in result
+            .cross_correlation
+            .iter()
+            .zip(&time_domain)
+            .enumerate()
+        {
+            assert!(
+                (fft - time_domain).abs() < 1e-4 * peak,
+                "shift {shift}: {fft} != {time_domain}"
+            );
+        }
+    }
+
+    #[test]
+    fn batch_samples_match_single_samples() {
+        let input = noise(3000);
+        let mut single = Computer::new(256, 1024);
+        let mut batch = single.clone();
+
+        let single_output: Vec<Sample> = (0..input.len()).map(|_| single.output_sample()).collect();
+        input
+            .iter()
+            .for_each(|&sample| single.record_sample(sample));
+
+        let mut batch_output = vec![0.0; input.len()];
+        // Uneven chunks exercise the buffers wrapping around mid-slice.
+        batch_output
+            .chunks_mut(700)
+            .for_each(|chunk| batch.fill_output(chunk));
+        input
+            .chunks(333)
+            .for_each(|chunk| batch.record_samples(chunk));
+
+        assert_eq!(batch_output, single_output);
+        assert_eq!(batch.input_sample_count(), single.input_sample_count());
+        assert_eq!(batch.output_sample_count(), single.output_sample_count());
+        assert!(batch.input_buffer().iter().eq(single.input_buffer().iter()));
+        assert_eq!(
+            batch.delay().unwrap().cross_correlation,
+            single.delay().unwrap().cross_correlation
+        );
+    }
+
+    #[test]
+    fn loopback_cancels_device_latency() {
+        let (latency, flight) = (50, 30);
+        let output = noise(4096);
+        let delayed = |delay: usize| -> Vec<Sample> {
+            (0..output.len())
+                .map(|index| index.checked_sub(delay).map_or(0.0, |index| output[index]))
+                .collect()
+        };
+
+        let mut computer = Computer::new(256, 1024).with_loopback();
+        computer.set_sample_rate_hz(48_000.0);
+        computer.record_output_samples(&output);
+        computer.record_samples(&delayed(latency + flight));
+        computer.record_loopback_samples(&delayed(latency));
+
+        let result = computer.delay().unwrap();
+        assert_eq!(result.delay_samples, latency + flight);
+        assert!((result.relative_delay_samples() - flight as f64).abs() < 0.05);
+        // The measured loopback delay replaces the configured system latency.
+        let setup = Setup {
+            path_length_m: Some(1.0),
+            system_latency_s: 1.0,
+        };
+        let time_of_flight_s = computer.measurement(&result, setup).time_of_flight_s();
+        assert!((time_of_flight_s - flight as f64 / 48_000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn no_delay_of_non_finite_input() {
+        let mut computer = Computer::new(256, 1024);
+        computer.record_output_samples(&noise(2048));
+        computer.record_samples(&vec![Sample::INFINITY; 2048]);
+
+        assert!(computer.delay().is_none());
+    }
+
+    #[test]
+    fn mls_finds_circular_delay() {
+        let mut computer = Computer::new_mls(10);
+        let period = 1023;
+        let delay = 900;
+
+        let mut output = vec![0.0; 3 * period];
+        computer.fill_output(&mut output);
+        // The delay wraps around the period, the first samples come from the previous one.
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| output[(index + period - delay) % period])
+            .collect();
+        computer.record_samples(&input);
+
+        let result = computer.delay().unwrap();
+        assert_eq!(result.delay_samples, delay);
+        assert!(result.quality.peak_to_sidelobe_ratio > 100.0);
+    }
+
+    #[test]
+    fn mls_interpolates_delay_at_the_seam() {
+        let mut computer = Computer::new_mls(10);
+        let period = 1023;
+
+        let mut output = vec![0.0; 3 * period];
+        computer.fill_output(&mut output);
+        // Half a sample of delay puts the peak between the first and the last phase shift.
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| 0.5 * (output[index % period] + output[(index + period - 1) % period]))
+            .collect();
+        computer.record_samples(&input);
+
+        let result = computer.delay().unwrap();
+        assert!(
+            (result.precise_delay_samples - 0.5).abs() < 0.05,
+            "delay {}",
+            result.precise_delay_samples
+        );
+    }
+
+    #[test]
+    fn golay_pair_has_no_sidelobes() {
+        let mut computer = Computer::new_golay(6);
+        let (length, period) = (64, 256);
+        let delay = 20;
+
+        let mut output = vec![0.0; 3 * period];
+        computer.fill_output(&mut output);
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| output[(index + period - delay) % period])
+            .collect();
+        computer.record_samples(&input);
+
+        let result = computer.delay().unwrap();
+        assert_eq!(result.delay_samples, delay);
+        assert_eq!(result.cross_correlation.len(), length);
+        // The sidelobes of the two sequences cancel out exactly.
+        let peak = result.cross_correlation.iter().copied().fold(0.0, f32::max);
+        for (shift, value) in result.cross_correlation.iter().enumerate() {
+            if *value != peak {
+                assert!(value.abs() < 1e-4 * peak, "shift {shift}: {value}");
+            }
+        }
+    }
+
+    #[test]
+    fn golay_pair_is_found_after_lost_output_sample() {
+        let mut generator = Computer::new_golay(6);
+        let period = 256;
+        let delay = 20;
+
+        let mut output = vec![0.0; 3 * period];
+        generator.fill_output(&mut output);
+        // A played sample got lost, the number of samples doesn't tell where the sequences are.
+        output.remove(100);
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| index.checked_sub(delay).map_or(0.0, |index| output[index]))
+            .collect();
+
+        let mut computer = Computer::new_golay(6);
+        computer.record_output_samples(&output);
+        computer.record_samples(&input);
+
+        assert_eq!(computer.delay().unwrap().delay_samples, delay);
+    }
+
+    #[test]
+    #[should_panic]
+    fn mls_excitation_stays() {
+        Computer::new_mls(10).with_excitation(WhiteNoise::new());
+    }
+
+    #[test]
+    fn tracking_search_locks_on_delay() {
+        for weighting in [Weighting::Unweighted, Weighting::Phat] {
+            let computer = computer_with_delayed_input(37, |output, index| output[index])
+                .with_weighting(weighting)
+                .with_tracking_search(8, 0.5);
+            let mut lock = SearchLock::default();
+
+            let full = computer.tracking_delay(&mut lock).unwrap();
+            assert_eq!(full.cross_correlation.len(), 257);
+            assert_eq!(lock.delay_samples(), Some(37));
+
+            let tracked = computer.tracking_delay(&mut lock).unwrap();
+            assert_eq!(tracked.delay_samples, 37);
+            assert!((tracked.precise_delay_samples - full.precise_delay_samples).abs() < 1e-3);
+            // Only the window is evaluated, quality included.
+            assert_eq!(tracked.cross_correlation.len(), 17);
+            assert!(tracked.quality.peak_to_sidelobe_ratio.is_finite());
+            assert_eq!(lock.delay_samples(), Some(37));
+
+            // The lock belongs to the caller, not to the computer or its clones.
+            assert_eq!(
+                computer.clone().delay().unwrap().cross_correlation.len(),
+                257
+            );
+        }
+    }
+
+    #[test]
+    fn tracking_search_falls_back_to_full_search() {
+        let mut lock = SearchLock::default();
+        let computer = computer_with_delayed_input(37, |output, index| output[index])
+            .with_tracking_search(8, 0.5);
+        computer.tracking_delay(&mut lock).unwrap();
+
+        // The delay jumped out of the window.
+        let computer = computer_with_delayed_input(100, |output, index| output[index])
+            .with_tracking_search(8, 0.5);
+        let result = computer.tracking_delay(&mut lock).unwrap();
+        assert_eq!(result.delay_samples, 100);
+        assert_eq!(lock.delay_samples(), Some(100));
+    }
+
+    #[test]
+    fn tracking_search_wraps_around_the_period() {
+        let mut computer = Computer::new_mls(10).with_tracking_search(8, 0.5);
+        let period = 1023;
+        let delay = 1020;
+
+        let mut output = vec![0.0; 3 * period];
+        computer.fill_output(&mut output);
+        let input: Vec<Sample> = (0..output.len())
+            .map(|index| output[(index + period - delay) % period])
+            .collect();
+        computer.record_samples(&input);
+
+        let mut lock = SearchLock::default();
+        computer.tracking_delay(&mut lock).unwrap();
+        let tracked = computer.tracking_delay(&mut lock).unwrap();
+        assert_eq!(tracked.delay_samples, delay);
+        assert_eq!(tracked.cross_correlation.len(), 17);
+        assert!((tracked.precise_delay_samples - delay as f64).abs() < 0.05);
+    }
+}
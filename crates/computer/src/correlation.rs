@@ -0,0 +1,542 @@
+use std::{fmt, sync::Arc};
+
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+use serde::{Deserialize, Serialize};
+
+use crate::Sample;
+
+/// Half-width (in frequency bins) of the moving average used to estimate (cross-)power spectra
+/// for weightings that need them. A single window gives very noisy per-bin estimates.
+const SPECTRUM_SMOOTHING_BINS: usize = 8;
+
+/// Fraction of the mean of a spectrum below which its bins are considered empty, see
+/// [noise_floor].
+const NOISE_FLOOR: f64 = 1e-6;
+
+/// Generalized cross-correlation weighting applied to the cross-power spectrum before it's
+/// transformed back to time domain.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Weighting {
+    /// Plain cross-correlation (dot product of the signals).
+    #[default]
+    Unweighted,
+    /// Phase transform. Whitens the cross-power spectrum so that only phase information is left.
+    Phat,
+    /// Smoothed coherence transform. Normalizes by the geometric mean of both power spectra.
+    Scot,
+    /// Maximum likelihood (Hannan-Thomson). Favours frequencies with high coherence.
+    Ml,
+}
+
+/// Cross-correlates a longer (output) signal with a shorter (input) signal in frequency domain.
+/// FFT plans are created once, on construction, and reused for every correlation.
+#[derive(Clone)]
+pub struct Correlator {
+    fft_size: usize,
+    weighting: Weighting,
+    forward: Arc<dyn RealToComplex<Sample>>,
+    inverse: Arc<dyn ComplexToReal<Sample>>,
+}
+
+impl Correlator {
+    /// Plan FFTs large enough to correlate signals of given lengths without circular aliasing.
+    pub fn new(output_len: usize, input_len: usize, weighting: Weighting) -> Self {
+        let fft_size = output_len.max(input_len).max(1).next_power_of_two();
+        Self::with_fft_size(fft_size, weighting)
+    }
+
+    /// Plan FFTs for circular correlation of two signals, each exactly one `period` long.
+    pub fn circular(period: usize, weighting: Weighting) -> Self {
+        Self::with_fft_size(period, weighting)
+    }
+
+    fn with_fft_size(fft_size: usize, weighting: Weighting) -> Self {
+        assert!(fft_size > 0, "FFT size must be non-zero");
+
+        let mut planner = RealFftPlanner::new();
+        Self {
+            fft_size,
+            weighting,
+            forward: planner.plan_fft_forward(fft_size),
+            inverse: planner.plan_fft_inverse(fft_size),
+        }
+    }
+
+    pub fn weighting(&self) -> Weighting {
+        self.weighting
+    }
+
+    pub fn set_weighting(&mut self, weighting: Weighting) {
+        self.weighting = weighting;
+    }
+
+    /// Return `sum(output[shift + i] * input[i])` for every `shift` in `0..shifts`,
+    /// with the configured weighting applied in frequency domain.
+    /// Output samples past the end of the output signal are considered to be zero, as long as
+    /// `shifts + input.len() - 1` doesn't exceed the FFT size. Otherwise the correlation wraps.
+    pub fn cross_correlate<'a>(
+        &self,
+        output: impl IntoIterator<Item = &'a Sample>,
+        input: impl IntoIterator<Item = &'a Sample>,
+        shifts: usize,
+    ) -> Vec<Sample> {
+        assert!(
+            shifts <= self.fft_size,
+            "can't compute more shifts than the FFT size"
+        );
+
+        let mut cross_spectrum = self.weighted_cross_spectrum(output, input);
+
+        let mut correlation = self.inverse.make_output_vec();
+        self.inverse
+            .process(&mut cross_spectrum, &mut correlation)
+            .expect("buffers are sized by the plan and spectrum edges are real");
+
+        // realfft doesn't normalize, so the round trip scales everything by fft_size.
+        let scale = 1.0 / self.fft_size as Sample;
+        correlation.truncate(shifts);
+        correlation.iter_mut().for_each(|value| *value *= scale);
+
+        correlation
+    }
+
+    /// Like [Correlator::cross_correlate], but evaluated only at given shifts, in their order.
+    /// Both signals are still transformed whole, only the inverse transform is replaced by direct
+    /// sums. That pays off for a few shifts only.
+    pub fn cross_correlate_at<'a>(
+        &self,
+        output: impl IntoIterator<Item = &'a Sample>,
+        input: impl IntoIterator<Item = &'a Sample>,
+        shifts: &[usize],
+    ) -> Vec<Sample> {
+        let cross_spectrum = self.weighted_cross_spectrum(output, input);
+        let last_bin = cross_spectrum.len() - 1;
+
+        shifts
+            .iter()
+            .map(|&shift| {
+                assert!(shift < self.fft_size, "shift must be within the FFT size");
+
+                // Inverse DFT at a single point. Bins above Nyquist mirror (as complex conjugates)
+                // those below it, so every bin but DC and Nyquist counts twice.
+                let angle = 2.0 * std::f64::consts::PI * shift as f64 / self.fft_size as f64;
+                let step = Complex::new(angle.cos(), angle.sin());
+                let mut rotation = Complex::new(1.0, 0.0);
+                let mut sum = 0.0;
+                for (bin, value) in cross_spectrum.iter().enumerate() {
+                    let multiplicity =
+                        if bin == 0 || (bin == last_bin && self.fft_size.is_multiple_of(2)) {
+                            1.0
+                        } else {
+                            2.0
+                        };
+                    sum += multiplicity * (to_f64(*value) * rotation).re;
+                    rotation *= step;
+                }
+
+                (sum / self.fft_size as f64) as Sample
+            })
+            .collect()
+    }
+
+    /// Spectrum of the correlation of the signals, with the configured weighting applied.
+    fn weighted_cross_spectrum<'a>(
+        &self,
+        output: impl IntoIterator<Item = &'a Sample>,
+        input: impl IntoIterator<Item = &'a Sample>,
+    ) -> Vec<Complex<Sample>> {
+        let output_spectrum = self.spectrum(output);
+        let input_spectrum = self.spectrum(input);
+
+        // Correlation is a convolution with time-reversed input, which translates to
+        // multiplication with complex-conjugated spectrum in the frequency domain.
+        let mut cross_spectrum: Vec<_> = output_spectrum
+            .iter()
+            .zip(input_spectrum.iter())
+            .map(|(output_bin, input_bin)| output_bin * input_bin.conj())
+            .collect();
+
+        self.apply_weighting(&mut cross_spectrum, &output_spectrum, &input_spectrum);
+
+        // The product of spectra of two real signals has zero imaginary parts at DC and Nyquist
+        // frequency. Rounding errors may break that, which realfft would complain about.
+        cross_spectrum[0].im = 0.0;
+        if self.fft_size.is_multiple_of(2) {
+            let last = cross_spectrum.len() - 1;
+            cross_spectrum[last].im = 0.0;
+        }
+
+        cross_spectrum
+    }
+
+    /// Scale every bin of the cross-power spectrum according to the configured weighting.
+    /// Weights are computed in double precision, spectra of long signals easily exceed the range
+    /// where single precision sums stay accurate.
+    fn apply_weighting(
+        &self,
+        cross_spectrum: &mut [Complex<Sample>],
+        output_spectrum: &[Complex<Sample>],
+        input_spectrum: &[Complex<Sample>],
+    ) {
+        let weights: Vec<f64> = match self.weighting {
+            Weighting::Unweighted => return,
+            Weighting::Phat => {
+                let magnitudes: Vec<f64> = cross_spectrum
+                    .iter()
+                    .map(|bin| to_f64(*bin).norm())
+                    .collect();
+                let floor = noise_floor(&magnitudes);
+
+                magnitudes
+                    .iter()
+                    .map(|magnitude| 1.0 / magnitude.max(floor))
+                    .collect()
+            }
+            Weighting::Scot => {
+                let output_power = smooth(&power(output_spectrum));
+                let input_power = smooth(&power(input_spectrum));
+                let floor = (noise_floor(&output_power) * noise_floor(&input_power)).sqrt();
+
+                output_power
+                    .iter()
+                    .zip(input_power.iter())
+                    .map(|(output_power, input_power)| {
+                        1.0 / (output_power * input_power).sqrt().max(floor)
+                    })
+                    .collect()
+            }
+            Weighting::Ml => {
+                let output_power = smooth(&power(output_spectrum));
+                let input_power = smooth(&power(input_spectrum));
+                let smoothed_cross_spectrum: Vec<Complex<f64>> = smooth(
+                    &cross_spectrum
+                        .iter()
+                        .map(|bin| to_f64(*bin))
+                        .collect::<Vec<_>>(),
+                );
+                let power_floor = noise_floor(&output_power) * noise_floor(&input_power);
+                let magnitude_floor = power_floor.sqrt();
+
+                smoothed_cross_spectrum
+                    .iter()
+                    .zip(output_power.iter().zip(input_power.iter()))
+                    .map(|(cross, (output_power, input_power))| {
+                        let cross_magnitude = cross.norm().max(magnitude_floor);
+                        let coherence = (cross.norm_sqr()
+                            / (output_power * input_power).max(power_floor))
+                        .min(1.0 - Sample::EPSILON as f64);
+
+                        coherence / ((1.0 - coherence) * cross_magnitude)
+                    })
+                    .collect()
+            }
+        };
+
+        cross_spectrum
+            .iter_mut()
+            .zip(weights)
+            .for_each(|(bin, weight)| *bin *= weight as Sample);
+    }
+
+    /// Zero-pad the signal to the FFT size and transform it.
+    fn spectrum<'a>(&self, signal: impl IntoIterator<Item = &'a Sample>) -> Vec<Complex<Sample>> {
+        let mut buffer = self.forward.make_input_vec();
+        buffer
+            .iter_mut()
+            .zip(signal)
+            .for_each(|(slot, &sample)| *slot = sample);
+
+        let mut spectrum = self.forward.make_output_vec();
+        self.forward
+            .process(&mut buffer, &mut spectrum)
+            .expect("buffers are sized by the plan");
+
+        spectrum
+    }
+}
+
+/// Squared magnitude of every bin of the spectrum.
+fn power(spectrum: &[Complex<Sample>]) -> Vec<f64> {
+    spectrum.iter().map(|bin| to_f64(*bin).norm_sqr()).collect()
+}
+
+fn to_f64(bin: Complex<Sample>) -> Complex<f64> {
+    Complex::new(bin.re as f64, bin.im as f64)
+}
+
+/// Level below which values of a (power or magnitude) spectrum hold no signal, only rounding
+/// noise that the weighting would otherwise amplify. It is [NOISE_FLOOR] times the mean value,
+/// but never zero so that silence doesn't divide by zero.
+fn noise_floor(values: &[f64]) -> f64 {
+    let mean = values.iter().sum::<f64>() / values.len().max(1) as f64;
+    (NOISE_FLOOR * mean).max(Sample::MIN_POSITIVE as f64)
+}
+
+/// Moving average over [SPECTRUM_SMOOTHING_BINS] bins on both sides of every bin.
+/// The window is truncated at the edges of the spectrum.
+fn smooth<T>(values: &[T]) -> Vec<T>
+where
+    T: Copy + Default + std::ops::Add<Output = T> + std::ops::Sub<Output = T>,
+    T: std::ops::Div<f64, Output = T>,
+{
+    // Prefix sums are only accurate enough in double precision. The differences of large single
+    // precision sums cancel catastrophically in the quiet parts of the spectrum.
+    let prefix_sums: Vec<T> = std::iter::once(T::default())
+        .chain(values.iter().scan(T::default(), |sum, &value| {
+            *sum = *sum + value;
+            Some(*sum)
+        }))
+        .collect();
+
+    (0..values.len())
+        .map(|index| {
+            let start = index.saturating_sub(SPECTRUM_SMOOTHING_BINS);
+            let end = (index + SPECTRUM_SMOOTHING_BINS + 1).min(values.len());
+            (prefix_sums[end] - prefix_sums[start]) / (end - start) as f64
+        })
+        .collect()
+}
+
+/// How many samples on either side of the main correlation peak still belong to it. Used to tell
+/// the main lobe apart from sidelobes.
+const MAIN_LOBE_HALF_WIDTH: usize = 4;
+
+/// Ratio of the main peak to the highest (absolute) correlation value outside the main lobe.
+pub fn peak_to_sidelobe_ratio(correlation: &[Sample], peak: usize) -> f64 {
+    let sidelobe = correlation
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| index.abs_diff(peak) > MAIN_LOBE_HALF_WIDTH)
+        .map(|(_, value)| value.abs())
+        .fold(0.0, Sample::max);
+
+    if sidelobe > 0.0 {
+        correlation[peak] as f64 / sidelobe as f64
+    } else {
+        f64::INFINITY
+    }
+}
+
+/// Find the highest local maximum outside the main lobe of the peak and return its distance
+/// from the peak in samples. Return None if there is no such maximum.
+pub fn second_peak_distance(correlation: &[Sample], peak: usize) -> Option<usize> {
+    (1..correlation.len().saturating_sub(1))
+        .filter(|index| index.abs_diff(peak) > MAIN_LOBE_HALF_WIDTH)
+        .filter(|&index| {
+            correlation[index] > correlation[index - 1]
+                && correlation[index] >= correlation[index + 1]
+        })
+        .max_by(|&a, &b| correlation[a].total_cmp(&correlation[b]))
+        .map(|index| index.abs_diff(peak))
+}
+
+/// Estimate the sub-sample position of the peak at `peak` index by fitting a parabola through it
+/// and its two neighbours. Return the offset (within -0.5..=0.5) of the parabola vertex from `peak`.
+/// A circular correlation covering a whole period wraps around, its first and last values are
+/// neighbours too.
+pub fn parabolic_peak_offset(correlation: &[Sample], peak: usize, circular: bool) -> f64 {
+    let length = correlation.len();
+    let (left, right) = if circular {
+        ((peak + length - 1) % length, (peak + 1) % length)
+    } else if peak == 0 || peak + 1 >= length {
+        // The peak is at the edge, there's no neighbour to fit the parabola through.
+        return 0.0;
+    } else {
+        (peak - 1, peak + 1)
+    };
+
+    let left = correlation[left] as f64;
+    let center = correlation[peak] as f64;
+    let right = correlation[right] as f64;
+
+    let curvature = left - 2.0 * center + right;
+    if curvature >= 0.0 {
+        // Not a local maximum (flat or convex), the vertex would be meaningless.
+        return 0.0;
+    }
+
+    (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+}
+
+impl fmt::Debug for Correlator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Correlator")
+            .field("fft_size", &self.fft_size)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    /// Deterministic signal with no particular structure.
+    fn signal(length: usize, seed: f32) -> Vec<Sample> {
+        (0..length)
+            .map(|index| {
+                let index = index as Sample;
+                (index * 0.37 * seed).sin() + 0.5 * (index * 1.31 + seed).cos()
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: &[Sample], expected: &[Sample]) {
+        assert_eq!(actual.len(), expected.len());
+        for (index, (actual, expected)) in actual.iter().zip(expected).enumerate() {
+            assert!(
+                (actual - expected).abs() < 1e-3 * expected.abs().max(1.0),
+                "shift {index}: {actual} != {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn linear_correlation_matches_brute_force() {
+        let output = signal(300, 1.0);
+        let input = signal(100, 2.0);
+        let shifts = output.len() - input.len() + 1;
+
+        let brute_force: Vec<Sample> = (0..shifts)
+            .map(|shift| {
+                input
+                    .iter()
+                    .enumerate()
+                    .map(|(index, input)| output[shift + index] * input)
+                    .sum()
+            })
+            .collect();
+
+        let correlator = Correlator::new(output.len(), input.len(), Weighting::Unweighted);
+        assert_close(
+            &correlator.cross_correlate(&output, &input, shifts),
+            &brute_force,
+        );
+    }
+
+    #[test]
+    fn circular_correlation_matches_brute_force() {
+        let period = 127;
+        let output = signal(period, 1.0);
+        let input = signal(period, 3.0);
+
+        let brute_force: Vec<Sample> = (0..period)
+            .map(|shift| {
+                input
+                    .iter()
+                    .enumerate()
+                    .map(|(index, input)| output[(shift + index) % period] * input)
+                    .sum()
+            })
+            .collect();
+
+        let correlator = Correlator::circular(period, Weighting::Unweighted);
+        assert_close(
+            &correlator.cross_correlate(&output, &input, period),
+            &brute_force,
+        );
+    }
+
+    #[test]
+    fn parabolic_offset_finds_vertex_of_sampled_parabola() {
+        let vertex = 5.3;
+        let correlation: Vec<Sample> = (0..10)
+            .map(|index| 10.0 - (index as Sample - vertex).powi(2))
+            .collect();
+
+        let offset = parabolic_peak_offset(&correlation, 5, false);
+        assert!((offset - 0.3).abs() < 1e-4, "offset {offset}");
+    }
+
+    #[test]
+    fn parabolic_offset_is_zero_at_edges() {
+        let correlation = [3.0, 2.0, 1.0];
+        assert_eq!(parabolic_peak_offset(&correlation, 0, false), 0.0);
+        assert_eq!(parabolic_peak_offset(&correlation, 2, false), 0.0);
+    }
+
+    #[test]
+    fn circular_parabolic_offset_wraps_around() {
+        // The peak lies between the last and the first value.
+        let correlation = [3.0, 1.0, 0.0, 2.0];
+        let offset = parabolic_peak_offset(&correlation, 0, true);
+        assert!((offset + 1.0 / 6.0).abs() < 1e-6, "offset {offset}");
+    }
+
+    #[test]
+    fn weightings_find_the_shift_of_a_delayed_signal() {
+        // Whitening needs a broadband signal, sines won't do.
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let output: Vec<Sample> = (0..1000).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let input: Vec<Sample> = output[123..623].to_vec();
+        let shifts = output.len() - input.len() + 1;
+
+        for weighting in [Weighting::Phat, Weighting::Scot, Weighting::Ml] {
+            let correlator = Correlator::new(output.len(), input.len(), weighting);
+            let correlation = correlator.cross_correlate(&output, &input, shifts);
+            assert!(correlation.iter().all(|value| value.is_finite()));
+
+            let peak = (0..shifts)
+                .max_by(|&a, &b| correlation[a].total_cmp(&correlation[b]))
+                .unwrap();
+            assert_eq!(peak, 123, "{weighting:?}");
+        }
+    }
+
+    #[test]
+    fn weightings_stay_finite_on_silence() {
+        let output = vec![0.0; 256];
+        let input = vec![0.0; 64];
+
+        for weighting in [Weighting::Phat, Weighting::Scot, Weighting::Ml] {
+            let correlator = Correlator::new(output.len(), input.len(), weighting);
+            let correlation = correlator.cross_correlate(&output, &input, 193);
+            assert!(
+                correlation.iter().all(|value| value.is_finite()),
+                "{weighting:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn smoothing_keeps_quiet_bins_after_loud_ones() {
+        // Single precision prefix sums would turn the quiet tail into noise around zero.
+        let mut values = vec![1e12; 100];
+        values.extend(vec![1.0; 100]);
+
+        let smoothed = smooth(&values);
+        assert!(smoothed[150..].iter().all(|&value| value == 1.0));
+    }
+
+    #[test]
+    fn correlation_at_shifts_matches_full_correlation() {
+        let mut rng = ChaCha8Rng::seed_from_u64(2);
+        let output: Vec<Sample> = (0..300).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let input: Vec<Sample> = output[40..140].to_vec();
+        let shifts = [0, 1, 39, 40, 41, 150, 200];
+
+        for weighting in [Weighting::Unweighted, Weighting::Phat, Weighting::Ml] {
+            let correlator = Correlator::new(output.len(), input.len(), weighting);
+            let full = correlator.cross_correlate(&output, &input, 201);
+            let expected: Vec<Sample> = shifts.iter().map(|&shift| full[shift]).collect();
+
+            assert_close(
+                &correlator.cross_correlate_at(&output, &input, &shifts),
+                &expected,
+            );
+        }
+
+        // Odd FFT size has no Nyquist bin.
+        let period = 127;
+        let correlator = Correlator::circular(period, Weighting::Scot);
+        let full = correlator.cross_correlate(&output[..period], &output[5..5 + period], period);
+        let expected: Vec<Sample> = shifts[..5].iter().map(|&shift| full[shift]).collect();
+        assert_close(
+            &correlator.cross_correlate_at(&output[..period], &output[5..5 + period], &shifts[..5]),
+            &expected,
+        );
+    }
+}
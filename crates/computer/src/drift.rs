@@ -0,0 +1,256 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    service::Analysis,
+    status::Status,
+    tracker::{Tracker, TrackerConfig},
+    Sample,
+};
+
+/// How long to collect delays before estimating the drift from them.
+const ESTIMATION_PERIOD: Duration = Duration::from_secs(10);
+/// Minimum number of delays the drift is estimated from.
+const MINIMUM_MEASUREMENTS: usize = 20;
+/// Largest correction of the drift in a single estimation period, in parts per million.
+const MAX_CORRECTION_PPM: f64 = 50.0;
+/// Largest drift that is ever compensated, in parts per million. Real clocks are off by tens of
+/// ppm, estimates far beyond that come from broken measurements.
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+/// Ratio of the actual input to output sample rate over the nominal one, shared between the drift
+/// estimating thread and the input stream resampler.
+#[derive(Debug, Clone)]
+pub struct SampleRateRatio(Arc<AtomicU64>);
+
+impl SampleRateRatio {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicU64::new(1.0_f64.to_bits())))
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, ratio: f64) {
+        self.0.store(ratio.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Clock offset of the input relative to the output, in parts per million.
+    pub fn ppm(&self) -> f64 {
+        (self.get() - 1.0) * 1e6
+    }
+}
+
+impl Default for SampleRateRatio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Resamples a stream by a slowly varying ratio using cubic (Catmull-Rom) interpolation.
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    /// Ratio of the nominal sample rates, e.g. 44.1 / 48 when a 44.1 kHz input feeds a 48 kHz
+    /// computer.
+    nominal_ratio: f64,
+    /// Correction of the nominal ratio for the clock drift.
+    ratio: SampleRateRatio,
+    /// The last four input samples.
+    history: [Sample; 4],
+    /// Position of the next output sample, in input samples after the second oldest one in the
+    /// history.
+    offset: f64,
+}
+
+impl Resampler {
+    pub fn new(ratio: SampleRateRatio) -> Self {
+        Self {
+            nominal_ratio: 1.0,
+            ratio,
+            history: [0.0; 4],
+            // Becomes zero with the first sample.
+            offset: 1.0,
+        }
+    }
+
+    /// Resample between different nominal sample rates, on top of the drift correction.
+    pub fn with_nominal_ratio(
+        mut self,
+        input_sample_rate_hz: f64,
+        output_sample_rate_hz: f64,
+    ) -> Self {
+        self.nominal_ratio = input_sample_rate_hz / output_sample_rate_hz;
+        self
+    }
+
+    /// Feed a single input sample and call `emit` for every output sample it produces. That is
+    /// usually one, but sometimes none or two, depending on the ratio.
+    pub fn process(&mut self, sample: Sample, mut emit: impl FnMut(Sample)) {
+        self.history = [self.history[1], self.history[2], self.history[3], sample];
+        self.offset -= 1.0;
+
+        let ratio = self.nominal_ratio * self.ratio.get();
+        while self.offset <= 1.0 {
+            emit(self.interpolate(self.offset as Sample));
+            self.offset += ratio;
+        }
+    }
+
+    fn interpolate(&self, t: Sample) -> Sample {
+        let [p0, p1, p2, p3] = self.history;
+
+        p1 + 0.5
+            * t
+            * (p2 - p0
+                + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3 + t * (3.0 * (p1 - p2) + p3 - p0)))
+    }
+}
+
+/// Spawn a thread that estimates how fast the delay walks due to the input and output clocks
+/// drifting apart and corrects the sample rate ratio used to resample the input.
+///
+/// The delay of the loopback is followed when there is one, as unlike the acoustic delay it doesn't
+/// change with the air. Results with confidence below `minimum_confidence` and those the [Tracker]
+/// rejects as outliers are left out, and the drift is fitted robustly, so a few wrong delays don't
+/// throw the estimate off. The correction is limited to [MAX_CORRECTION_PPM] per estimation period
+/// and [MAX_DRIFT_PPM] in total. Every estimate is reported to the status.
+pub fn compensate_clock_drift(
+    analyses: Receiver<Arc<Analysis>>,
+    ratio: SampleRateRatio,
+    minimum_confidence: f64,
+    status: Status,
+) {
+    thread::spawn(move || {
+        let mut delays = Vec::new();
+        let mut tracker = Tracker::new(TrackerConfig::default());
+        let mut last_time_s = None;
+        let mut period_start = Instant::now();
+        for analysis in analyses {
+            let result = &analysis.result;
+            // Time the delay by the input samples, that is more precise than the wall clock.
+            let time_s = result.input_samples.end as f64 / analysis.computer.sample_rate_hz();
+            let delay_samples = result
+                .loopback_delay_samples
+                .unwrap_or(result.precise_delay_samples);
+
+            if result.is_confident(minimum_confidence) {
+                let elapsed_s = last_time_s.map_or(0.0, |last_time_s| time_s - last_time_s);
+                last_time_s = Some(time_s);
+
+                if !tracker.update_delay(delay_samples, elapsed_s).outlier {
+                    delays.push((time_s, delay_samples));
+                }
+            }
+
+            if result.instant.saturating_duration_since(period_start) < ESTIMATION_PERIOD {
+                continue;
+            }
+
+            if delays.len() >= MINIMUM_MEASUREMENTS {
+                // The delay grows when the input produces more samples than the output consumes.
+                let samples_per_s = robust_slope(&delays);
+                let residual = samples_per_s / analysis.computer.sample_rate_hz();
+                ratio.set(corrected_ratio(ratio.get(), residual));
+
+                status.set(
+                    "clock drift",
+                    format!(
+                        "{:.2} ppm (residual {:.2} ppm)",
+                        ratio.ppm(),
+                        residual * 1e6
+                    ),
+                );
+            }
+
+            // Delays measured with the previous ratio would skew the next estimate.
+            delays.clear();
+            period_start = Instant::now();
+        }
+    });
+}
+
+/// Apply the relative `residual` drift to the ratio, within the limits of the correction.
+fn corrected_ratio(ratio: f64, residual: f64) -> f64 {
+    let max_correction = MAX_CORRECTION_PPM * 1e-6;
+    let max_drift = MAX_DRIFT_PPM * 1e-6;
+
+    (ratio * (1.0 + residual.clamp(-max_correction, max_correction)))
+        .clamp(1.0 - max_drift, 1.0 + max_drift)
+}
+
+/// Theil-Sen estimate of the slope of a line through the points, i.e. the median of slopes between
+/// all pairs of points. Unlike least squares it ignores up to ~29 % of outliers.
+fn robust_slope(points: &[(f64, f64)]) -> f64 {
+    let mut slopes: Vec<f64> = points
+        .iter()
+        .enumerate()
+        .flat_map(|(index, (x1, y1))| {
+            points[index + 1..]
+                .iter()
+                .filter(move |(x2, _)| x2 != x1)
+                .map(move |(x2, y2)| (y2 - y1) / (x2 - x1))
+        })
+        .collect();
+
+    if slopes.is_empty() {
+        return 0.0;
+    }
+
+    let count = slopes.len();
+    let (lower, median, _) = slopes.select_nth_unstable_by(count / 2, f64::total_cmp);
+    if count % 2 == 1 {
+        *median
+    } else {
+        // The other middle value is the largest of the lower half.
+        let below = lower.iter().copied().fold(f64::MIN, f64::max);
+        (below + *median) / 2.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn robust_slope_ignores_outliers() {
+        let mut points: Vec<(f64, f64)> = (0..100)
+            .map(|index| {
+                let x = index as f64 * 0.1;
+                (x, 100.0 + 0.5 * x)
+            })
+            .collect();
+        // Second peaks winning now and then.
+        for index in (0..100).step_by(7) {
+            points[index].1 += 300.0;
+        }
+
+        assert!((robust_slope(&points) - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn robust_slope_of_degenerate_points_is_zero() {
+        assert_eq!(robust_slope(&[]), 0.0);
+        assert_eq!(robust_slope(&[(1.0, 2.0), (1.0, 5.0)]), 0.0);
+    }
+
+    #[test]
+    fn correction_is_clamped() {
+        assert_eq!(corrected_ratio(1.0, 10e-6), 1.0 + 10e-6);
+        assert_eq!(corrected_ratio(1.0, 1.0), 1.0 + MAX_CORRECTION_PPM * 1e-6);
+        assert_eq!(corrected_ratio(1.0, -1.0), 1.0 - MAX_CORRECTION_PPM * 1e-6);
+
+        let mut ratio = 1.0;
+        for _ in 0..100 {
+            ratio = corrected_ratio(ratio, 1.0);
+        }
+        assert_eq!(ratio, 1.0 + MAX_DRIFT_PPM * 1e-6);
+    }
+}
@@ -0,0 +1,599 @@
+use std::{f64::consts::PI, fmt};
+
+use rand::distributions::Distribution;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use statrs::distribution::Normal;
+
+use crate::Sample;
+
+/// Peak amplitude of the deterministic signals. Leaves a bit of headroom below cpal's (-1, 1) range.
+const PEAK_AMPLITUDE: f64 = 0.9;
+/// Standard deviation of the noise signals. Utilizes entire cpal range (-1, 1) without excessive
+/// clipping. With STD of 0.5 about 5% of samples end up outside the range and are clamped.
+const NOISE_STANDARD_DEVIATION: f64 = 0.5;
+
+/// Signal played by the speaker and searched for in the microphone input.
+pub trait Excitation: fmt::Debug + Send + Sync {
+    /// Return the next audio sample in cpal's F32 format.
+    fn next_sample(&mut self) -> Sample;
+
+    /// Adapt to a new sample rate, e.g. the one negotiated with the audio devices. Signals defined
+    /// in samples rather than in hertz ignore it.
+    fn set_sample_rate_hz(&mut self, _sample_rate_hz: f64) {}
+
+    /// Clone the excitation into a new box. Needed to make `Box<dyn Excitation>` cloneable.
+    fn clone_box(&self) -> Box<dyn Excitation>;
+}
+
+impl Clone for Box<dyn Excitation> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+/// Random noise with normal distribution to approximate real-world noise.
+#[derive(Debug, Clone)]
+pub struct WhiteNoise {
+    distribution: Normal,
+    rng: ChaCha8Rng,
+}
+
+impl WhiteNoise {
+    /// Noise seeded from the operating system's entropy, different every time.
+    pub fn new() -> Self {
+        Self {
+            distribution: Normal::new(0.0, NOISE_STANDARD_DEVIATION)
+                .expect("mean and standard deviation are sane"),
+            rng: ChaCha8Rng::from_entropy(),
+        }
+    }
+
+    /// Draw the noise from given generator. A seeded one makes the noise repeatable. Unlike
+    /// `StdRng`, ChaCha8 is guaranteed to give the same sequence on every platform and version.
+    pub fn with_rng(mut self, rng: ChaCha8Rng) -> Self {
+        self.rng = rng;
+        self
+    }
+}
+
+impl Default for WhiteNoise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Excitation for WhiteNoise {
+    fn next_sample(&mut self) -> Sample {
+        self.distribution.sample(&mut self.rng).clamp(-1.0, 1.0) as Sample
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+/// White noise band-pass filtered to given frequency range. Useful to avoid frequencies the speaker
+/// can't reproduce or where the room is noisy.
+#[derive(Debug, Clone)]
+pub struct BandLimitedNoise {
+    distribution: Normal,
+    rng: ChaCha8Rng,
+    low_frequency_hz: f64,
+    high_frequency_hz: f64,
+    high_pass: Biquad,
+    low_pass: Biquad,
+    /// Compensates for the power removed by the filters.
+    gain: f64,
+}
+
+impl BandLimitedNoise {
+    /// Noise seeded from the operating system's entropy, see [WhiteNoise::new].
+    pub fn new(low_frequency_hz: f64, high_frequency_hz: f64, sample_rate_hz: f64) -> Self {
+        let (high_pass, low_pass, gain) =
+            Self::filters(low_frequency_hz, high_frequency_hz, sample_rate_hz);
+
+        Self {
+            distribution: Normal::new(0.0, NOISE_STANDARD_DEVIATION)
+                .expect("mean and standard deviation are sane"),
+            rng: ChaCha8Rng::from_entropy(),
+            low_frequency_hz,
+            high_frequency_hz,
+            high_pass,
+            low_pass,
+            gain,
+        }
+    }
+
+    /// High-pass and low-pass filters of the band and the gain compensating for them.
+    fn filters(
+        low_frequency_hz: f64,
+        high_frequency_hz: f64,
+        sample_rate_hz: f64,
+    ) -> (Biquad, Biquad, f64) {
+        assert!(
+            0.0 < low_frequency_hz
+                && low_frequency_hz < high_frequency_hz
+                && high_frequency_hz < sample_rate_hz / 2.0,
+            "frequency band must be non-empty and below Nyquist frequency"
+        );
+
+        let passed_fraction = 2.0 * (high_frequency_hz - low_frequency_hz) / sample_rate_hz;
+
+        (
+            Biquad::high_pass(low_frequency_hz, sample_rate_hz),
+            Biquad::low_pass(high_frequency_hz, sample_rate_hz),
+            1.0 / passed_fraction.sqrt(),
+        )
+    }
+
+    /// Draw the noise from given generator, see [WhiteNoise::with_rng].
+    pub fn with_rng(mut self, rng: ChaCha8Rng) -> Self {
+        self.rng = rng;
+        self
+    }
+}
+
+impl Excitation for BandLimitedNoise {
+    fn next_sample(&mut self) -> Sample {
+        let white = self.distribution.sample(&mut self.rng);
+        let filtered = self.low_pass.process(self.high_pass.process(white));
+
+        (filtered * self.gain).clamp(-1.0, 1.0) as Sample
+    }
+
+    fn set_sample_rate_hz(&mut self, sample_rate_hz: f64) {
+        (self.high_pass, self.low_pass, self.gain) = Self::filters(
+            self.low_frequency_hz,
+            self.high_frequency_hz,
+            sample_rate_hz,
+        );
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+/// Periodic maximum-length sequence generated by a linear-feedback shift register.
+/// Its period is `2^order - 1` samples.
+#[derive(Debug, Clone)]
+pub struct MaximumLengthSequence {
+    order: u32,
+    taps: u32,
+    state: u32,
+}
+
+impl MaximumLengthSequence {
+    /// Supported orders of the sequence.
+    pub const ORDERS: std::ops::RangeInclusive<u32> = 2..=20;
+
+    /// Construct the sequence generator. Panic when the order isn't in [Self::ORDERS].
+    pub fn new(order: u32) -> Self {
+        // Tap positions (counted from 1) of primitive polynomials for every supported order.
+        let taps: &[u32] = match order {
+            2 => &[2, 1],
+            3 => &[3, 2],
+            4 => &[4, 3],
+            5 => &[5, 3],
+            6 => &[6, 5],
+            7 => &[7, 6],
+            8 => &[8, 6, 5, 4],
+            9 => &[9, 5],
+            10 => &[10, 7],
+            11 => &[11, 9],
+            12 => &[12, 6, 4, 1],
+            13 => &[13, 4, 3, 1],
+            14 => &[14, 5, 3, 1],
+            15 => &[15, 14],
+            16 => &[16, 15, 13, 4],
+            17 => &[17, 14],
+            18 => &[18, 11],
+            19 => &[19, 6, 2, 1],
+            20 => &[20, 17],
+            _ => panic!(
+                "unsupported MLS order {order}, expected one of {:?}",
+                Self::ORDERS
+            ),
+        };
+
+        Self {
+            order,
+            // The register shifts towards the least significant bit, so tap `t` feeds back the
+            // sample from `t` steps ago, which sits at bit `order - t`.
+            taps: taps.iter().fold(0, |mask, tap| mask | 1 << (order - tap)),
+            // Any non-zero state works, all-zeros would get stuck.
+            state: 1,
+        }
+    }
+
+    pub fn period(&self) -> usize {
+        (1 << self.order) - 1
+    }
+}
+
+impl Excitation for MaximumLengthSequence {
+    fn next_sample(&mut self) -> Sample {
+        let bit = self.state & 1;
+        let feedback = (self.state & self.taps).count_ones() & 1;
+        self.state = (self.state >> 1) | (feedback << (self.order - 1));
+
+        if bit == 1 {
+            PEAK_AMPLITUDE as Sample
+        } else {
+            -PEAK_AMPLITUDE as Sample
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+/// Golay complementary pair played in a loop, each sequence twice in a row. Only the sum of
+/// autocorrelations of the two sequences has no sidelobes, so the computer needs to correlate each
+/// sequence on its own, see [crate::computer::Computer::new_golay]. The first copy of a sequence
+/// makes the input during the second one a circularly shifted copy of it.
+#[derive(Debug, Clone)]
+pub struct GolayPair {
+    period: Vec<Sample>,
+    position: usize,
+}
+
+impl GolayPair {
+    /// Construct a pair of sequences with `2^order` samples each.
+    pub fn new(order: u32) -> Self {
+        let mut a = vec![1.0];
+        let mut b = vec![1.0];
+        for _ in 0..order {
+            let next_a = a.iter().chain(b.iter()).copied().collect();
+            let next_b = a.iter().copied().chain(b.iter().map(|x| -x)).collect();
+            a = next_a;
+            b = next_b;
+        }
+
+        let period = [&a, &a, &b, &b]
+            .into_iter()
+            .flatten()
+            .map(|x: &f64| (x * PEAK_AMPLITUDE) as Sample)
+            .collect();
+
+        Self {
+            period,
+            position: 0,
+        }
+    }
+
+    /// Number of samples of each of the sequences.
+    pub fn sequence_length(&self) -> usize {
+        self.period.len() / 4
+    }
+
+    /// Samples of one period: the first sequence twice, then the second one twice.
+    pub fn period(&self) -> &[Sample] {
+        &self.period
+    }
+}
+
+impl Excitation for GolayPair {
+    fn next_sample(&mut self) -> Sample {
+        let sample = self.period[self.position];
+        self.position = (self.position + 1) % self.period.len();
+        sample
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sweep {
+    /// Frequency increases linearly with time.
+    Linear,
+    /// Frequency increases exponentially with time. Spends the same time in every octave.
+    Exponential,
+}
+
+/// Sine wave sweeping from start to end frequency, repeated over and over.
+#[derive(Debug, Clone)]
+pub struct Chirp {
+    start_frequency_hz: f64,
+    end_frequency_hz: f64,
+    sweep: Sweep,
+    sample_rate_hz: f64,
+    duration_samples: usize,
+    position: usize,
+}
+
+impl Chirp {
+    pub fn new(
+        start_frequency_hz: f64,
+        end_frequency_hz: f64,
+        sweep: Sweep,
+        duration_samples: usize,
+        sample_rate_hz: f64,
+    ) -> Self {
+        assert!(duration_samples > 0, "chirp must not be empty");
+        assert!(
+            sweep == Sweep::Linear || (start_frequency_hz > 0.0 && end_frequency_hz > 0.0),
+            "exponential chirp must have positive frequencies"
+        );
+
+        Self {
+            start_frequency_hz,
+            end_frequency_hz,
+            sweep,
+            sample_rate_hz,
+            duration_samples,
+            position: 0,
+        }
+    }
+
+    /// Phase (in cycles) at given time since the start of the chirp.
+    fn phase(&self, seconds: f64) -> f64 {
+        let duration = self.duration_samples as f64 / self.sample_rate_hz;
+        let (start, end) = (self.start_frequency_hz, self.end_frequency_hz);
+
+        match self.sweep {
+            Sweep::Linear => start * seconds + (end - start) * seconds.powi(2) / (2.0 * duration),
+            Sweep::Exponential if start == end => start * seconds,
+            Sweep::Exponential => {
+                let ratio = end / start;
+                start * duration / ratio.ln() * (ratio.powf(seconds / duration) - 1.0)
+            }
+        }
+    }
+}
+
+impl Excitation for Chirp {
+    fn next_sample(&mut self) -> Sample {
+        // Compute the phase from the position within the chirp so that errors don't accumulate.
+        let phase = self.phase(self.position as f64 / self.sample_rate_hz);
+        self.position = (self.position + 1) % self.duration_samples;
+
+        (PEAK_AMPLITUDE * (2.0 * PI * phase).sin()) as Sample
+    }
+
+    fn set_sample_rate_hz(&mut self, sample_rate_hz: f64) {
+        self.sample_rate_hz = sample_rate_hz;
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+/// Sum of sine waves of given frequencies. Uses Schroeder phases to keep the crest factor low.
+#[derive(Debug, Clone)]
+pub struct MultiTone {
+    frequencies_hz: Vec<f64>,
+    /// Phase increment (in cycles per sample) and the current phase of every tone.
+    tones: Vec<(f64, f64)>,
+}
+
+impl MultiTone {
+    pub fn new(frequencies_hz: &[f64], sample_rate_hz: f64) -> Self {
+        assert!(
+            !frequencies_hz.is_empty(),
+            "there must be at least one tone"
+        );
+
+        let count = frequencies_hz.len() as f64;
+        let tones = frequencies_hz
+            .iter()
+            .enumerate()
+            .map(|(index, frequency_hz)| {
+                let k = index as f64 + 1.0;
+                let schroeder_phase = -k * (k - 1.0) / (2.0 * count);
+                (
+                    frequency_hz / sample_rate_hz,
+                    schroeder_phase.rem_euclid(1.0),
+                )
+            })
+            .collect();
+
+        Self {
+            frequencies_hz: frequencies_hz.to_vec(),
+            tones,
+        }
+    }
+}
+
+impl Excitation for MultiTone {
+    fn next_sample(&mut self) -> Sample {
+        let count = self.tones.len() as f64;
+        let sum = self
+            .tones
+            .iter_mut()
+            .map(|(increment, phase)| {
+                let value = (2.0 * PI * *phase).sin();
+                *phase = (*phase + *increment).rem_euclid(1.0);
+                value
+            })
+            .sum::<f64>();
+
+        (PEAK_AMPLITUDE * sum / count) as Sample
+    }
+
+    fn set_sample_rate_hz(&mut self, sample_rate_hz: f64) {
+        for ((increment, _), frequency_hz) in self.tones.iter_mut().zip(&self.frequencies_hz) {
+            *increment = frequency_hz / sample_rate_hz;
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn Excitation> {
+        Box::new(self.clone())
+    }
+}
+
+/// Second order IIR filter with Butterworth response (coefficients from the Audio EQ Cookbook).
+#[derive(Debug, Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    inputs: [f64; 2],
+    outputs: [f64; 2],
+}
+
+impl Biquad {
+    fn low_pass(cutoff_hz: f64, sample_rate_hz: f64) -> Self {
+        let (cos, alpha) = Self::cos_and_alpha(cutoff_hz, sample_rate_hz);
+        Self::normalized(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            cos,
+            alpha,
+        )
+    }
+
+    fn high_pass(cutoff_hz: f64, sample_rate_hz: f64) -> Self {
+        let (cos, alpha) = Self::cos_and_alpha(cutoff_hz, sample_rate_hz);
+        Self::normalized(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            cos,
+            alpha,
+        )
+    }
+
+    fn cos_and_alpha(cutoff_hz: f64, sample_rate_hz: f64) -> (f64, f64) {
+        let omega = 2.0 * PI * cutoff_hz / sample_rate_hz;
+        // Q of 1/sqrt(2) gives maximally flat (Butterworth) pass band.
+        (omega.cos(), omega.sin() / 2.0_f64.sqrt())
+    }
+
+    fn normalized(b: [f64; 3], cos: f64, alpha: f64) -> Self {
+        let a0 = 1.0 + alpha;
+        Self {
+            b: b.map(|coefficient| coefficient / a0),
+            a: [-2.0 * cos / a0, (1.0 - alpha) / a0],
+            inputs: [0.0; 2],
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.inputs[0] + self.b[2] * self.inputs[1]
+            - self.a[0] * self.outputs[0]
+            - self.a[1] * self.outputs[1];
+
+        self.inputs = [input, self.inputs[0]];
+        self.outputs = [output, self.outputs[0]];
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(excitation: &mut impl Excitation, length: usize) -> Vec<Sample> {
+        (0..length).map(|_| excitation.next_sample()).collect()
+    }
+
+    /// Aperiodic autocorrelation at lag `lag`.
+    fn autocorrelation(sequence: &[Sample], lag: usize) -> f64 {
+        sequence
+            .iter()
+            .zip(&sequence[lag..])
+            .map(|(a, b)| *a as f64 * *b as f64)
+            .sum()
+    }
+
+    #[test]
+    fn seeded_noise_repeats() {
+        let noise = |seed| {
+            take(
+                &mut WhiteNoise::new().with_rng(ChaCha8Rng::seed_from_u64(seed)),
+                64,
+            )
+        };
+
+        assert_eq!(noise(1), noise(1));
+        assert_ne!(noise(1), noise(2));
+    }
+
+    #[test]
+    fn signals_adapt_to_new_sample_rate() {
+        let signals: [(Box<dyn Excitation>, Box<dyn Excitation>); 3] = [
+            (
+                Box::new(
+                    BandLimitedNoise::new(500.0, 12_000.0, 48_000.0)
+                        .with_rng(ChaCha8Rng::seed_from_u64(1)),
+                ),
+                Box::new(
+                    BandLimitedNoise::new(500.0, 12_000.0, 44_100.0)
+                        .with_rng(ChaCha8Rng::seed_from_u64(1)),
+                ),
+            ),
+            (
+                Box::new(Chirp::new(500.0, 12_000.0, Sweep::Linear, 1000, 48_000.0)),
+                Box::new(Chirp::new(500.0, 12_000.0, Sweep::Linear, 1000, 44_100.0)),
+            ),
+            (
+                Box::new(MultiTone::new(&[701.0, 1303.0], 48_000.0)),
+                Box::new(MultiTone::new(&[701.0, 1303.0], 44_100.0)),
+            ),
+        ];
+
+        for (mut adapted, mut expected) in signals {
+            adapted.set_sample_rate_hz(44_100.0);
+            let adapted: Vec<Sample> = (0..256).map(|_| adapted.next_sample()).collect();
+            let expected: Vec<Sample> = (0..256).map(|_| expected.next_sample()).collect();
+            assert_eq!(adapted, expected, "{expected:?}");
+        }
+    }
+
+    #[test]
+    fn golay_pair_is_complementary() {
+        let mut pair = GolayPair::new(5);
+        let length = 1 << 5;
+        assert_eq!(pair.sequence_length(), length);
+        let period = take(&mut pair, 4 * length);
+        assert_eq!(period, pair.period());
+        assert_eq!(period[..length], period[length..2 * length]);
+        assert_eq!(period[2 * length..3 * length], period[3 * length..]);
+        let (a, b) = (&period[..length], &period[2 * length..3 * length]);
+
+        let energy = autocorrelation(a, 0) + autocorrelation(b, 0);
+        assert!(energy > 0.0);
+        for lag in 1..length {
+            let sum = autocorrelation(a, lag) + autocorrelation(b, lag);
+            assert!(sum.abs() < 1e-9 * energy, "lag {lag}: {sum}");
+        }
+    }
+
+    #[test]
+    fn mls_repeats_with_its_period() {
+        for order in MaximumLengthSequence::ORDERS.take(9) {
+            let mut mls = MaximumLengthSequence::new(order);
+            let period = mls.period();
+            let samples = take(&mut mls, 2 * period);
+
+            assert_eq!(samples[..period], samples[period..], "order {order}");
+            // No shorter period: the state visits every non-zero value exactly once.
+            let ones = samples[..period].iter().filter(|&&x| x > 0.0).count();
+            assert_eq!(ones, period.div_ceil(2), "order {order}");
+        }
+    }
+
+    #[test]
+    fn mls_circular_autocorrelation_is_two_valued() {
+        for order in MaximumLengthSequence::ORDERS.take(9) {
+            let mut mls = MaximumLengthSequence::new(order);
+            let period = mls.period();
+            let samples: Vec<f64> = take(&mut mls, period)
+                .into_iter()
+                .map(|x| (x as f64 / PEAK_AMPLITUDE).round())
+                .collect();
+
+            for lag in 0..period {
+                let correlation: f64 = (0..period)
+                    .map(|index| samples[index] * samples[(index + lag) % period])
+                    .sum();
+                let expected = if lag == 0 { period as f64 } else { -1.0 };
+                assert_eq!(correlation, expected, "order {order}, lag {lag}");
+            }
+        }
+    }
+}
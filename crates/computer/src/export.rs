@@ -0,0 +1,247 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::Deref,
+    path::Path,
+    sync::{mpsc::Receiver, Arc},
+    time::SystemTime,
+};
+
+use eyre::{Context, Result};
+use serde::Serialize;
+
+use crate::{
+    bidirectional::{BidirectionalComputer, BidirectionalResult},
+    computer::{Computer, DelayResult},
+    service::{Analysis, BidirectionalAnalysis},
+    status::Status,
+    units::{temperature_from_speed_of_sound_c, Measurement},
+};
+
+/// Columns of the CSV format, in the order of the [Record] fields.
+const CSV_HEADER: &str = "timestamp_s,stream_time_s,delay_samples,delay_s,time_of_flight_s,\
+    confidence,speed_of_sound_m_s,temperature_c,wind_m_s,backward_delay_samples";
+
+/// Format of the measurements written for other programs to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Comma-separated values with a header. Unknown values are left empty.
+    Csv,
+    /// One JSON object per line. Unknown values are null.
+    JsonLines,
+}
+
+/// A single measurement. In bidirectional mode the delay is that of the forward path.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Record {
+    /// When the delay was computed, in seconds since the Unix epoch.
+    pub timestamp_s: f64,
+    /// Time of the last input sample the delay was computed from, counted from the first one.
+    /// Unlike the timestamp, it is the same on every replay of a recording.
+    pub stream_time_s: f64,
+    /// Delay with sub-sample precision, relative to the loopback if there is one.
+    pub delay_samples: f64,
+    pub delay_s: f64,
+    pub time_of_flight_s: f64,
+    pub confidence: f64,
+    /// Derived from the time of flight when the path length is known.
+    pub speed_of_sound_m_s: Option<f64>,
+    pub temperature_c: Option<f64>,
+    /// Component of the wind velocity along the path, only measured in bidirectional mode.
+    pub wind_m_s: Option<f64>,
+    pub backward_delay_samples: Option<f64>,
+}
+
+impl Record {
+    pub fn new(computer: &Computer, result: &DelayResult, measurement: &Measurement) -> Self {
+        let delay_samples = result.relative_delay_samples();
+
+        Self {
+            timestamp_s: unix_time_s(result.wall_clock),
+            stream_time_s: result.input_samples.end as f64 / computer.sample_rate_hz(),
+            delay_samples,
+            delay_s: delay_samples / computer.sample_rate_hz(),
+            time_of_flight_s: measurement.time_of_flight_s(),
+            confidence: result.quality.confidence(),
+            speed_of_sound_m_s: measurement.speed_of_sound_m_s(),
+            temperature_c: measurement.temperature_c(),
+            wind_m_s: None,
+            backward_delay_samples: None,
+        }
+    }
+
+    pub fn from_analysis(analysis: &Analysis) -> Self {
+        Self::new(&analysis.computer, &analysis.result, &analysis.measurement)
+    }
+
+    /// Record of both paths. Speed of sound and temperature are those of still air, i.e. with the
+    /// wind cancelled out. Confidence is the lower of the two.
+    pub fn from_bidirectional(
+        computer: &BidirectionalComputer,
+        result: &BidirectionalResult,
+    ) -> Self {
+        let forward = computer.forward();
+        let measurement = forward.measurement(&result.forward, computer.setup());
+        let speed_of_sound_m_s = result.wind.map(|wind| wind.speed_of_sound_m_s);
+
+        Self {
+            confidence: result
+                .forward
+                .quality
+                .confidence()
+                .min(result.backward.quality.confidence()),
+            speed_of_sound_m_s,
+            temperature_c: speed_of_sound_m_s.map(temperature_from_speed_of_sound_c),
+            wind_m_s: result.wind.map(|wind| wind.speed_m_s),
+            backward_delay_samples: Some(result.backward.relative_delay_samples()),
+            ..Self::new(forward, &result.forward, &measurement)
+        }
+    }
+}
+
+/// Writes records in given format, each flushed right away so that readers see them live.
+pub struct RecordWriter {
+    format: ExportFormat,
+    writer: Box<dyn Write + Send>,
+    header_written: bool,
+}
+
+impl RecordWriter {
+    pub fn new(format: ExportFormat, writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            format,
+            writer,
+            header_written: false,
+        }
+    }
+
+    /// Write to given file, or to the standard output if there is none.
+    pub fn create(format: ExportFormat, path: Option<&Path>) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(BufWriter::new(
+                File::create(path).wrap_err_with(|| format!("creating {}", path.display()))?,
+            )),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Self::new(format, writer))
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        match self.format {
+            ExportFormat::Csv => {
+                if !self.header_written {
+                    writeln!(self.writer, "{CSV_HEADER}")?;
+                    self.header_written = true;
+                }
+                writeln!(
+                    self.writer,
+                    "{:.6},{:.6},{:.3},{:.9},{:.9},{:.4},{},{},{},{}",
+                    record.timestamp_s,
+                    record.stream_time_s,
+                    record.delay_samples,
+                    record.delay_s,
+                    record.time_of_flight_s,
+                    record.confidence,
+                    csv_optional(record.speed_of_sound_m_s, 3),
+                    csv_optional(record.temperature_c, 2),
+                    csv_optional(record.wind_m_s, 3),
+                    csv_optional(record.backward_delay_samples, 3),
+                )?;
+            }
+            ExportFormat::JsonLines => {
+                serde_json::to_writer(&mut self.writer, record)?;
+                writeln!(self.writer)?;
+            }
+        }
+
+        self.writer.flush().wrap_err("writing measurement")
+    }
+}
+
+/// Write a record of every analysis with confidence at least `minimum_confidence`, until the
+/// analyses stop coming. Errors, e.g. a closed pipe, are reported to the status and stop the
+/// export.
+pub fn export_measurements(
+    analyses: Receiver<Arc<Analysis>>,
+    mut writer: RecordWriter,
+    minimum_confidence: f64,
+    status: Status,
+) {
+    for analysis in analyses {
+        if !analysis.result.is_confident(minimum_confidence) {
+            continue;
+        }
+
+        if let Err(err) = writer.write(&Record::from_analysis(&analysis)) {
+            status.set("export", format!("failed: {err:#}"));
+            return;
+        }
+    }
+}
+
+/// Bidirectional variant of [export_measurements]. Measurements where any of the paths has
+/// confidence below `minimum_confidence` are left out.
+pub fn export_bidirectional_measurements(
+    analyses: Receiver<Arc<BidirectionalAnalysis>>,
+    mut writer: RecordWriter,
+    minimum_confidence: f64,
+    status: Status,
+) {
+    for analysis in analyses {
+        let BidirectionalAnalysis { computer, result } = analysis.deref();
+        if !result.forward.is_confident(minimum_confidence)
+            || !result.backward.is_confident(minimum_confidence)
+        {
+            continue;
+        }
+
+        if let Err(err) = writer.write(&Record::from_bidirectional(computer, result)) {
+            status.set("export", format!("failed: {err:#}"));
+            return;
+        }
+    }
+}
+
+fn csv_optional(value: Option<f64>, decimals: usize) -> String {
+    value.map_or(String::new(), |value| format!("{value:.decimals$}"))
+}
+
+fn unix_time_s(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{units::Setup, Sample};
+
+    #[test]
+    fn record_delay_is_relative_to_the_loopback() {
+        let (latency, flight) = (50, 30);
+        let mut rng = ChaCha8Rng::seed_from_u64(4);
+        let output: Vec<Sample> = (0..4096).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let delayed = |delay: usize| -> Vec<Sample> {
+            (0..output.len())
+                .map(|index| index.checked_sub(delay).map_or(0.0, |index| output[index]))
+                .collect()
+        };
+
+        let mut computer = Computer::new(256, 1024).with_loopback();
+        computer.set_sample_rate_hz(48_000.0);
+        computer.record_output_samples(&output);
+        computer.record_samples(&delayed(latency + flight));
+        computer.record_loopback_samples(&delayed(latency));
+
+        let result = computer.delay().unwrap();
+        let measurement = computer.measurement(&result, Setup::default());
+        let record = Record::new(&computer, &result, &measurement);
+        assert!((record.delay_samples - flight as f64).abs() < 0.05);
+        assert!((record.delay_s - flight as f64 / 48_000.0).abs() < 1e-6);
+        assert!((record.time_of_flight_s - record.delay_s).abs() < 1e-9);
+    }
+}
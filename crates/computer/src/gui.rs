@@ -1,12 +1,12 @@
 use std::{
-    ops::Deref,
-    sync::{Arc, RwLock},
+    sync::{mpsc::Receiver, Arc, RwLock},
+    time::{Duration, Instant},
 };
 
 use eyre::{Context, Ok, Result};
 use self_similarity_matrix::SelfSimilarityMatrix;
 
-use crate::{computer::Computer, simulator::Simulator};
+use crate::{service::Analysis, simulator::Simulator};
 use wgpu::Instance;
 use winit::{
     event::{Event, KeyEvent, WindowEvent},
@@ -16,8 +16,13 @@ use winit::{
 
 mod self_similarity_matrix;
 
+/// How often to show the latest measurement in the window title.
+const TITLE_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Visualize the latest analyses. When simulating, keys tweak the simulator: A/S increase and
+/// decrease the gain, D/F the delay and N/M decrease and increase the signal to noise ratio.
 pub fn run_gui(
-    computer: Arc<RwLock<Computer>>,
+    analyses: Receiver<Arc<Analysis>>,
     simulator: Option<Arc<RwLock<Simulator>>>,
 ) -> Result<()> {
     let event_loop = EventLoop::new().wrap_err("creating event loop<")?;
@@ -27,7 +32,7 @@ pub fn run_gui(
         .build(&event_loop)
         .wrap_err("creating GUI window")?;
 
-    pollster::block_on(run(event_loop, window, computer, simulator));
+    pollster::block_on(run(event_loop, window, analyses, simulator));
 
     Ok(())
 }
@@ -35,7 +40,7 @@ pub fn run_gui(
 async fn run(
     event_loop: EventLoop<()>,
     window: Window,
-    computer: Arc<RwLock<Computer>>,
+    analyses: Receiver<Arc<Analysis>>,
     simulator: Option<Arc<RwLock<Simulator>>>,
 ) {
     let mut size = window.inner_size();
@@ -60,10 +65,15 @@ async fn run(
         .await
         .expect("Failed to create device");
 
+    // The first analysis tells the sizes of the buffers to visualize.
+    let Some(mut analysis) = analyses.recv().ok() else {
+        return;
+    };
+
     let swapchain_format = surface.get_capabilities(&adapter).formats[0];
     let visualization = SelfSimilarityMatrix::new(
-        computer.read().unwrap().output_buffer().capacity(),
-        computer.read().unwrap().input_buffer().capacity(),
+        analysis.computer.output_buffer().capacity(),
+        analysis.computer.input_buffer().capacity(),
         &device,
         swapchain_format,
     );
@@ -73,12 +83,7 @@ async fn run(
         .unwrap();
     surface.configure(&device, &config);
 
-    if simulator.is_some() {
-        println!("Use keys to tweak simulator params:");
-        println!("A/S to increase/decrease gain");
-        println!("D/F to increase/decrease delay");
-        println!("N/M to decrease/increase signal to noise ratio");
-    }
+    let mut last_title_update = Instant::now();
 
     let window = &window;
     let res = event_loop.run(move |event, target| {
@@ -114,35 +119,39 @@ async fn run(
                     if pressed_str == "a" {
                         let mut simulator = simulator.write().unwrap();
                         simulator.gain *= 1.1;
-                        println!("gain: {}", simulator.gain);
                     } else if pressed_str == "s" {
                         let mut simulator = simulator.write().unwrap();
                         simulator.gain *= 0.9;
-                        println!("gain: {}", simulator.gain);
                     } else if pressed_str == "n" {
                         let mut simulator = simulator.write().unwrap();
                         simulator.signal_to_noise_ratio *= 0.9;
-                        println!("signal to noise ratio: {}", simulator.signal_to_noise_ratio);
                     } else if pressed_str == "m" {
                         let mut simulator = simulator.write().unwrap();
                         simulator.signal_to_noise_ratio *= 1.1;
-                        println!("signal to noise ratio: {}", simulator.signal_to_noise_ratio);
                     } else if pressed_str == "d" {
                         let mut simulator = simulator.write().unwrap();
                         let delay = simulator.delay_samples().saturating_add(5);
                         simulator.set_delay(delay);
-                        println!("delay: {}", delay);
                     } else if pressed_str == "f" {
                         let mut simulator = simulator.write().unwrap();
                         let delay = simulator.delay_samples().saturating_sub(5);
                         simulator.set_delay(delay);
-                        println!("delay: {}", delay);
                     }
                 }
             }
             WindowEvent::RedrawRequested => {
-                let computer = computer.read().unwrap().deref().clone();
-                let delay_samples = computer.delay().map(|res| res.delay_samples).unwrap_or(0);
+                // Show the latest analysis, skip any we didn't manage to draw.
+                if let Some(latest) = analyses.try_iter().last() {
+                    analysis = latest;
+                }
+
+                if last_title_update.elapsed() > TITLE_UPDATE_INTERVAL {
+                    window.set_title(&format!(
+                        "Audio-anemometer Visualization - {}",
+                        analysis.measurement
+                    ));
+                    last_title_update = Instant::now();
+                }
 
                 let frame: wgpu::SurfaceTexture = surface
                     .get_current_texture()
@@ -152,9 +161,9 @@ async fn run(
                     .create_view(&wgpu::TextureViewDescriptor::default());
 
                 let commands = visualization.render(
-                    computer.output_buffer().iter(),
-                    computer.input_buffer().iter(),
-                    delay_samples,
+                    analysis.computer.output_buffer().iter(),
+                    analysis.computer.input_buffer().iter(),
+                    analysis.result.delay_samples,
                     &queue,
                     view,
                     &device,
@@ -176,7 +185,7 @@ async fn run(
 async fn get_surface_and_adapter(
     instance: Instance,
     window: &Window,
-) -> (wgpu::Surface, wgpu::Adapter) {
+) -> (wgpu::Surface<'_>, wgpu::Adapter) {
     let surface = instance.create_surface(window).unwrap();
     let adapter = instance
         .request_adapter(&wgpu::RequestAdapterOptions {
@@ -1,22 +1,729 @@
 use std::{
     sync::{Arc, RwLock},
+    thread,
     time::Duration,
 };
 
 use color_eyre::eyre::Result;
 use cpal::{
     traits::{DeviceTrait, HostTrait, StreamTrait},
-    SampleFormat, Stream,
+    BuildStreamError, Device, FromSample, SampleFormat, SampleRate, SizedSample, Stream,
+    StreamConfig, StreamInstant, SupportedStreamConfig, SupportedStreamConfigRange,
 };
-use eyre::{eyre, Context, ContextCompat};
+use eyre::{bail, eyre, Context, ContextCompat};
 
-use crate::computer::Computer;
+use crate::{
+    bidirectional::BidirectionalComputer,
+    computer::Computer,
+    drift::{Resampler, SampleRateRatio},
+    queue::{queue, Producer},
+    status::Status,
+    Sample,
+};
+
+/// Which channels of the input device to record.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputChannels {
+    /// Channel with the acoustic microphone.
+    pub microphone: usize,
+    /// Channel with an electrical loopback of the output. The computer must be constructed
+    /// [Computer::with_loopback] for it to be used.
+    pub loopback: Option<usize>,
+}
+
+/// Number of frames the queues between the audio callbacks and the analysis thread can hold.
+/// More than a second of audio at common sample rates.
+const QUEUE_CAPACITY: usize = 1 << 16;
+/// How often the analysis thread moves samples from the queues to the computer.
+const ANALYSIS_INTERVAL: Duration = Duration::from_millis(5);
+/// Number of samples converted at once from or to the device's sample format.
+const CONVERSION_BUFFER_SAMPLES: usize = 1024;
+
+/// What the audio callbacks hand over to the analysis thread.
+#[derive(Debug, Clone, Copy)]
+enum Event<F> {
+    /// Instant at which the next frame was played or captured.
+    Timestamp(StreamInstant),
+    Frame(F),
+    /// Number of frames that didn't fit into the queue right before this event.
+    Dropped(usize),
+}
+
+/// Producer end of an event queue. Counts frames that don't fit into the queue and reports them
+/// by [Event::Dropped] as soon as there's room again.
+struct EventSender<F> {
+    producer: Producer<Event<F>>,
+    dropped_frames: usize,
+}
+
+impl<F: Copy> EventSender<F> {
+    fn new(producer: Producer<Event<F>>) -> Self {
+        Self {
+            producer,
+            dropped_frames: 0,
+        }
+    }
+
+    fn send(&mut self, event: Event<F>) {
+        if self.dropped_frames > 0 && self.producer.push(Event::Dropped(self.dropped_frames)) {
+            self.dropped_frames = 0;
+        }
+
+        // Nothing may overtake the report of dropped frames.
+        let sent = self.dropped_frames == 0 && self.producer.push(event);
+        if !sent && matches!(event, Event::Frame(_)) {
+            self.dropped_frames += 1;
+        }
+    }
+}
+
+/// Keeps the played and the captured frames aligned when the queues overflow. The computer relies
+/// on the n-th played and the n-th captured frame being (about) simultaneous, so as many frames
+/// as one stream dropped are skipped in the other one.
+#[derive(Debug, Default)]
+struct Realignment {
+    /// Frames lost by the played and the captured stream so far, dropped or skipped.
+    lost_played: usize,
+    lost_captured: usize,
+    /// Frames dropped because the queues overflowed, reported and not yet reported.
+    dropped: usize,
+    reported: usize,
+}
+
+impl Realignment {
+    fn played_dropped(&mut self, frames: usize) {
+        self.lost_played += frames;
+        self.dropped += frames;
+    }
+
+    fn captured_dropped(&mut self, frames: usize) {
+        self.lost_captured += frames;
+        self.dropped += frames;
+    }
+
+    /// Whether to skip the next played frame to catch up with the captured ones.
+    fn skip_played(&mut self) -> bool {
+        let skip = self.lost_captured > self.lost_played;
+        self.lost_played += skip as usize;
+        skip
+    }
+
+    /// Whether to skip the next captured frame to catch up with the played ones.
+    fn skip_captured(&mut self) -> bool {
+        let skip = self.lost_played > self.lost_captured;
+        self.lost_captured += skip as usize;
+        skip
+    }
+
+    /// Warn when the analysis thread didn't keep up and the queues overflowed since the last call.
+    fn report(&mut self, status: &Status) {
+        if self.dropped > self.reported {
+            status.set(
+                "audio queues",
+                format!("overflowed, dropped {} frames so far", self.dropped),
+            );
+            self.reported = self.dropped;
+        }
+    }
+}
+
+/// Samples drained from the queues, handed over to the computer at once. That's cheaper than one by
+/// one, mostly when the computer records them.
+#[derive(Debug, Default)]
+struct Batches {
+    played: Vec<Sample>,
+    microphone: Vec<Sample>,
+    loopback: Vec<Sample>,
+}
+
+impl Batches {
+    fn record_played(&mut self, computer: &mut Computer) {
+        computer.record_output_samples(&self.played);
+        self.played.clear();
+    }
+
+    fn record_captured(&mut self, computer: &mut Computer) {
+        computer.record_samples(&self.microphone);
+        computer.record_loopback_samples(&self.loopback);
+        self.microphone.clear();
+        self.loopback.clear();
+    }
+}
 
+/// Play the computer's excitation and record the input. The audio callbacks neither lock nor
+/// allocate. They generate and capture samples on their own and pass them through queues to an
+/// analysis thread, which feeds the computer. The thread stops once the streams are dropped.
 pub fn run_real_world_audio(
     computer: Arc<RwLock<Computer>>,
     input_device_name: Option<String>,
     output_device_name: Option<String>,
+    input_channels: InputChannels,
+    sample_rate_ratio: Option<SampleRateRatio>,
+    status: Status,
+) -> Result<(Stream, Stream)> {
+    let (input_device, output_device) =
+        find_devices(input_device_name, output_device_name, &status)?;
+
+    let highest_channel = input_channels
+        .loopback
+        .map_or(input_channels.microphone, |loopback| {
+            loopback.max(input_channels.microphone)
+        });
+    let (input_config, output_config) = negotiate_configs(
+        &input_device,
+        &output_device,
+        highest_channel + 1,
+        1,
+        &status,
+    )?;
+
+    let input_sample_rate_hz = input_config.sample_rate().0 as f64;
+    let output_sample_rate_hz = output_config.sample_rate().0 as f64;
+    let mut excitation = {
+        let mut computer = computer.write().unwrap();
+        computer.set_sample_rate_hz(output_sample_rate_hz);
+        if let Some(recorder) = computer.recorder() {
+            recorder.set_devices(input_device.name().ok(), output_device.name().ok());
+        }
+        computer.excitation()
+    };
+
+    let (played, mut played_queue) = queue(QUEUE_CAPACITY);
+    let mut played = EventSender::new(played);
+    let output_channels = output_config.channels() as usize;
+    let output_stream = build_output_stream(
+        &output_device,
+        &output_config,
+        status.clone(),
+        move |output, playback| {
+            played.send(Event::Timestamp(playback));
+
+            assert_eq!(output.len() % output_channels, 0);
+            output
+                .chunks_exact_mut(output_channels)
+                .for_each(|channels| {
+                    let sample = excitation.next_sample();
+                    channels.fill(sample);
+                    played.send(Event::Frame(sample));
+                });
+        },
+    )?;
+
+    let (captured, mut captured_queue) = queue(QUEUE_CAPACITY);
+    let mut captured = EventSender::new(captured);
+    let channel_count = input_config.channels() as usize;
+    let input_stream = build_input_stream(
+        &input_device,
+        &input_config,
+        status.clone(),
+        move |data, capture| {
+            captured.send(Event::Timestamp(capture));
+
+            for channels in data.chunks_exact(channel_count) {
+                captured.send(Event::Frame((
+                    channels[input_channels.microphone],
+                    input_channels.loopback.map(|loopback| channels[loopback]),
+                )));
+            }
+        },
+    )?;
+
+    // Resample the input to the output sample rate and clock, if needed. Both channels need the
+    // same treatment.
+    let mut resamplers =
+        (sample_rate_ratio.is_some() || input_sample_rate_hz != output_sample_rate_hz).then(|| {
+            let resampler = Resampler::new(sample_rate_ratio.unwrap_or_default())
+                .with_nominal_ratio(input_sample_rate_hz, output_sample_rate_hz);
+            (resampler.clone(), resampler)
+        });
+
+    thread::spawn(move || {
+        let mut epoch = StreamEpoch::default();
+        let mut realignment = Realignment::default();
+        let mut batches = Batches::default();
+        while !played_queue.is_abandoned() || !captured_queue.is_abandoned() {
+            {
+                let mut computer = computer.write().unwrap();
+
+                // Drain the output first, the input always comes later. A timestamp belongs to the
+                // sample after it, so the samples before it are recorded first.
+                while let Some(event) = played_queue.pop() {
+                    match event {
+                        Event::Timestamp(playback) => {
+                            batches.record_played(&mut computer);
+                            computer.tag_output_buffer(epoch.seconds(playback))
+                        }
+                        Event::Frame(_) if realignment.skip_played() => {}
+                        Event::Frame(sample) => batches.played.push(sample),
+                        Event::Dropped(frames) => realignment.played_dropped(frames),
+                    }
+                }
+                batches.record_played(&mut computer);
+
+                while let Some(event) = captured_queue.pop() {
+                    let (microphone, loopback) = match event {
+                        Event::Timestamp(capture) => {
+                            batches.record_captured(&mut computer);
+                            computer.tag_input_buffer(epoch.seconds(capture));
+                            continue;
+                        }
+                        Event::Frame(_) if realignment.skip_captured() => continue,
+                        Event::Frame(frame) => frame,
+                        Event::Dropped(frames) => {
+                            realignment.captured_dropped(frames);
+                            continue;
+                        }
+                    };
+                    // Electrical loopback is strong enough, it doesn't need the gain.
+                    let microphone = microphone * 100.0;
+
+                    match resamplers.as_mut() {
+                        None => {
+                            batches.microphone.push(microphone);
+                            batches.loopback.extend(loopback);
+                        }
+                        Some((microphone_resampler, loopback_resampler)) => {
+                            microphone_resampler
+                                .process(microphone, |sample| batches.microphone.push(sample));
+                            if let Some(loopback) = loopback {
+                                loopback_resampler
+                                    .process(loopback, |sample| batches.loopback.push(sample));
+                            }
+                        }
+                    }
+                }
+                batches.record_captured(&mut computer);
+            }
+
+            realignment.report(&status);
+            thread::sleep(ANALYSIS_INTERVAL);
+        }
+    });
+
+    output_stream.play()?;
+    input_stream.play()?;
+
+    Ok((output_stream, input_stream))
+}
+
+/// Like [run_real_world_audio] but plays the forward and the backward path on the first two output
+/// channels and records them from the first two input channels.
+pub fn run_real_world_bidirectional_audio(
+    computer: Arc<RwLock<BidirectionalComputer>>,
+    input_device_name: Option<String>,
+    output_device_name: Option<String>,
+    status: Status,
 ) -> Result<(Stream, Stream)> {
+    let (input_device, output_device) =
+        find_devices(input_device_name, output_device_name, &status)?;
+
+    let (input_config, output_config) =
+        negotiate_configs(&input_device, &output_device, 2, 2, &status).wrap_err(
+            "bidirectional measurement needs at least two input and two output channels",
+        )?;
+
+    let input_sample_rate_hz = input_config.sample_rate().0 as f64;
+    let output_sample_rate_hz = output_config.sample_rate().0 as f64;
+    let mut excitations = {
+        let mut computer = computer.write().unwrap();
+        computer.set_sample_rate_hz(output_sample_rate_hz);
+        computer.excitations()
+    };
+
+    let (played, mut played_queue) = queue(QUEUE_CAPACITY);
+    let mut played = EventSender::new(played);
+    let output_channels = output_config.channels() as usize;
+    let output_stream = build_output_stream(
+        &output_device,
+        &output_config,
+        status.clone(),
+        move |output, playback| {
+            played.send(Event::Timestamp(playback));
+
+            assert_eq!(output.len() % output_channels, 0);
+            output
+                .chunks_exact_mut(output_channels)
+                .for_each(|channels| {
+                    let frame = excitations
+                        .each_mut()
+                        .map(|excitation| excitation.next_sample());
+                    channels[..2].copy_from_slice(&frame);
+                    // Keep any other channels silent.
+                    channels[2..].fill(0.0);
+                    played.send(Event::Frame(frame));
+                });
+        },
+    )?;
+
+    let (captured, mut captured_queue) = queue(QUEUE_CAPACITY);
+    let mut captured = EventSender::new(captured);
+    let input_channels = input_config.channels() as usize;
+    let input_stream = build_input_stream(
+        &input_device,
+        &input_config,
+        status.clone(),
+        move |data, capture| {
+            captured.send(Event::Timestamp(capture));
+
+            for channels in data.chunks_exact(input_channels) {
+                captured.send(Event::Frame([channels[0], channels[1]]));
+            }
+        },
+    )?;
+
+    let mut resamplers = (input_sample_rate_hz != output_sample_rate_hz).then(|| {
+        let resampler = Resampler::new(SampleRateRatio::new())
+            .with_nominal_ratio(input_sample_rate_hz, output_sample_rate_hz);
+        [resampler.clone(), resampler]
+    });
+
+    thread::spawn(move || {
+        let mut forward_samples = Vec::new();
+        let mut epoch = StreamEpoch::default();
+        let mut realignment = Realignment::default();
+        while !played_queue.is_abandoned() || !captured_queue.is_abandoned() {
+            {
+                let mut computer = computer.write().unwrap();
+
+                // Drain the output first, the input always comes later.
+                while let Some(event) = played_queue.pop() {
+                    match event {
+                        Event::Timestamp(playback) => {
+                            computer.tag_output_buffer(epoch.seconds(playback))
+                        }
+                        Event::Frame(_) if realignment.skip_played() => {}
+                        Event::Frame(frame) => computer.record_output_frame(frame),
+                        Event::Dropped(frames) => realignment.played_dropped(frames),
+                    }
+                }
+
+                while let Some(event) = captured_queue.pop() {
+                    let frame = match event {
+                        Event::Timestamp(capture) => {
+                            computer.tag_input_buffer(epoch.seconds(capture));
+                            continue;
+                        }
+                        Event::Frame(_) if realignment.skip_captured() => continue,
+                        Event::Frame(frame) => frame.map(|sample| sample * 100.0),
+                        Event::Dropped(frames) => {
+                            realignment.captured_dropped(frames);
+                            continue;
+                        }
+                    };
+
+                    match resamplers.as_mut() {
+                        None => computer.record_frame(frame),
+                        Some([forward_resampler, backward_resampler]) => {
+                            // Both resamplers share the ratio, hence they emit the same number of
+                            // samples.
+                            forward_samples.clear();
+                            forward_resampler
+                                .process(frame[0], |sample| forward_samples.push(sample));
+                            let mut forward = forward_samples.iter();
+                            backward_resampler.process(frame[1], |backward| {
+                                computer.record_frame([*forward.next().unwrap(), backward])
+                            });
+                        }
+                    }
+                }
+            }
+
+            realignment.report(&status);
+            thread::sleep(ANALYSIS_INTERVAL);
+        }
+    });
+
+    output_stream.play()?;
+    input_stream.play()?;
+
+    Ok((output_stream, input_stream))
+}
+
+/// Sample formats we can convert from and to, from the most preferred.
+const SAMPLE_FORMATS: [SampleFormat; 4] = [
+    SampleFormat::F32,
+    SampleFormat::I32,
+    SampleFormat::I16,
+    SampleFormat::U16,
+];
+/// Sample rates to try when the devices' defaults don't match.
+const COMMON_SAMPLE_RATES_HZ: [u32; 4] = [48_000, 44_100, 96_000, 88_200];
+
+/// Pick input and output stream configs with at least given number of channels and a sample format
+/// we can convert. Prefers a sample rate both devices support. When there is none, each device
+/// runs at its default rate and the input needs to be resampled.
+fn negotiate_configs(
+    input_device: &Device,
+    output_device: &Device,
+    minimum_input_channels: usize,
+    minimum_output_channels: usize,
+    status: &Status,
+) -> Result<(SupportedStreamConfig, SupportedStreamConfig)> {
+    let input_ranges = usable_configs(
+        input_device
+            .supported_input_configs()
+            .wrap_err("listing input configs")?,
+        minimum_input_channels,
+    );
+    if input_ranges.is_empty() {
+        bail!(
+            "input device has no config with at least {minimum_input_channels} channels \
+            in any of the supported sample formats {SAMPLE_FORMATS:?}"
+        );
+    }
+    let output_ranges = usable_configs(
+        output_device
+            .supported_output_configs()
+            .wrap_err("listing output configs")?,
+        minimum_output_channels,
+    );
+    if output_ranges.is_empty() {
+        bail!(
+            "output device has no config with at least {minimum_output_channels} channels \
+            in any of the supported sample formats {SAMPLE_FORMATS:?}"
+        );
+    }
+
+    let input_default_rate = input_device
+        .default_input_config()
+        .ok()
+        .map(|c| c.sample_rate());
+    let output_default_rate = output_device
+        .default_output_config()
+        .ok()
+        .map(|c| c.sample_rate());
+
+    let mut candidate_rates = output_default_rate
+        .into_iter()
+        .chain(input_default_rate)
+        .chain(COMMON_SAMPLE_RATES_HZ.map(SampleRate));
+    let common_rate_configs = candidate_rates.find_map(|rate| {
+        Some((
+            config_with_rate(&input_ranges, rate)?,
+            config_with_rate(&output_ranges, rate)?,
+        ))
+    });
+    let (input_config, output_config) = common_rate_configs.unwrap_or_else(|| {
+        status.set(
+            "sample rate",
+            "input and output devices have no sample rate in common, resampling the input",
+        );
+        let input_config = input_default_rate
+            .and_then(|rate| config_with_rate(&input_ranges, rate))
+            .unwrap_or_else(|| input_ranges[0].with_max_sample_rate());
+        let output_config = output_default_rate
+            .and_then(|rate| config_with_rate(&output_ranges, rate))
+            .unwrap_or_else(|| output_ranges[0].with_max_sample_rate());
+        (input_config, output_config)
+    });
+
+    status.set(
+        "streams",
+        format!(
+            "{} 🔊 -> 🎤 {}",
+            describe_config(&output_config),
+            describe_config(&input_config)
+        ),
+    );
+
+    Ok((input_config, output_config))
+}
+
+fn describe_config(config: &SupportedStreamConfig) -> String {
+    format!(
+        "{} Hz, {} channels, {}",
+        config.sample_rate().0,
+        config.channels(),
+        config.sample_format()
+    )
+}
+
+/// Configs in a convertible sample format with enough channels, from the most preferred.
+fn usable_configs(
+    configs: impl Iterator<Item = SupportedStreamConfigRange>,
+    minimum_channels: usize,
+) -> Vec<SupportedStreamConfigRange> {
+    let mut configs: Vec<_> = configs
+        .filter(|config| config.channels() as usize >= minimum_channels)
+        .filter(|config| SAMPLE_FORMATS.contains(&config.sample_format()))
+        .collect();
+    configs.sort_by_key(|config| {
+        let format_preference = SAMPLE_FORMATS
+            .iter()
+            .position(|format| *format == config.sample_format());
+        (format_preference, config.channels())
+    });
+    configs
+}
+
+fn config_with_rate(
+    configs: &[SupportedStreamConfigRange],
+    sample_rate: SampleRate,
+) -> Option<SupportedStreamConfig> {
+    configs
+        .iter()
+        .find_map(|config| (*config).try_with_sample_rate(sample_rate))
+}
+
+/// Build an input stream of any of the [SAMPLE_FORMATS] that hands the data over as f32, together
+/// with the capture instant of the first frame. Neither locks nor allocates in the callback.
+fn build_input_stream(
+    device: &Device,
+    config: &SupportedStreamConfig,
+    status: Status,
+    on_data: impl FnMut(&[f32], StreamInstant) + Send + 'static,
+) -> Result<Stream> {
+    fn build<T>(
+        device: &Device,
+        config: &StreamConfig,
+        status: Status,
+        mut on_data: impl FnMut(&[f32], StreamInstant) + Send + 'static,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample,
+        f32: FromSample<T>,
+    {
+        let channels = config.channels as usize;
+        let sample_rate_hz = config.sample_rate.0 as f64;
+        // Convert whole frames only.
+        let chunk_len = CONVERSION_BUFFER_SAMPLES / channels * channels;
+        let mut buffer = [0.0; CONVERSION_BUFFER_SAMPLES];
+
+        device.build_input_stream(
+            config,
+            move |data: &[T], info| {
+                let capture = info.timestamp().capture;
+
+                for (index, chunk) in data.chunks(chunk_len).enumerate() {
+                    let converted = &mut buffer[..chunk.len()];
+                    converted
+                        .iter_mut()
+                        .zip(chunk)
+                        .for_each(|(converted, sample)| *converted = sample.to_sample());
+
+                    let preceding_frames = index * chunk_len / channels;
+                    on_data(converted, later(capture, preceding_frames, sample_rate_hz));
+                }
+            },
+            move |err| status.set("capture", format!("error: {err}")),
+            Some(Duration::from_millis(20)),
+        )
+    }
+
+    if config.channels() as usize > CONVERSION_BUFFER_SAMPLES {
+        bail!(
+            "input devices with {} channels aren't supported",
+            config.channels()
+        );
+    }
+
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build::<f32>(device, &stream_config, status, on_data),
+        SampleFormat::I32 => build::<i32>(device, &stream_config, status, on_data),
+        SampleFormat::I16 => build::<i16>(device, &stream_config, status, on_data),
+        SampleFormat::U16 => build::<u16>(device, &stream_config, status, on_data),
+        format => bail!("unsupported input sample format {format}"),
+    };
+
+    stream.wrap_err("building input stream")
+}
+
+/// Build an output stream of any of the [SAMPLE_FORMATS] that is filled as f32, given the playback
+/// instant of the first frame. Neither locks nor allocates in the callback.
+fn build_output_stream(
+    device: &Device,
+    config: &SupportedStreamConfig,
+    status: Status,
+    fill: impl FnMut(&mut [f32], StreamInstant) + Send + 'static,
+) -> Result<Stream> {
+    fn build<T>(
+        device: &Device,
+        config: &StreamConfig,
+        status: Status,
+        mut fill: impl FnMut(&mut [f32], StreamInstant) + Send + 'static,
+    ) -> Result<Stream, BuildStreamError>
+    where
+        T: SizedSample + FromSample<f32>,
+    {
+        let channels = config.channels as usize;
+        let sample_rate_hz = config.sample_rate.0 as f64;
+        // Convert whole frames only.
+        let chunk_len = CONVERSION_BUFFER_SAMPLES / channels * channels;
+        let mut buffer = [0.0; CONVERSION_BUFFER_SAMPLES];
+
+        device.build_output_stream(
+            config,
+            move |output: &mut [T], info| {
+                let playback = info.timestamp().playback;
+
+                for (index, chunk) in output.chunks_mut(chunk_len).enumerate() {
+                    let preceding_frames = index * chunk_len / channels;
+                    let samples = &mut buffer[..chunk.len()];
+                    fill(samples, later(playback, preceding_frames, sample_rate_hz));
+
+                    chunk
+                        .iter_mut()
+                        .zip(samples.iter())
+                        .for_each(|(output, sample)| *output = T::from_sample(*sample));
+                }
+            },
+            move |err| status.set("playback", format!("error: {err}")),
+            Some(Duration::from_millis(20)),
+        )
+    }
+
+    if config.channels() as usize > CONVERSION_BUFFER_SAMPLES {
+        bail!(
+            "output devices with {} channels aren't supported",
+            config.channels()
+        );
+    }
+
+    let stream_config = config.config();
+    let stream = match config.sample_format() {
+        SampleFormat::F32 => build::<f32>(device, &stream_config, status, fill),
+        SampleFormat::I32 => build::<i32>(device, &stream_config, status, fill),
+        SampleFormat::I16 => build::<i16>(device, &stream_config, status, fill),
+        SampleFormat::U16 => build::<u16>(device, &stream_config, status, fill),
+        format => bail!("unsupported output sample format {format}"),
+    };
+
+    stream.wrap_err("building output stream")
+}
+
+/// Instant of the frame `frames` after the one at `instant`.
+fn later(instant: StreamInstant, frames: usize, sample_rate_hz: f64) -> StreamInstant {
+    instant
+        .add(Duration::from_secs_f64(frames as f64 / sample_rate_hz))
+        .unwrap_or(instant)
+}
+
+/// Converts stream instants to seconds since the first instant it converted. Instants of streams
+/// of the same host are comparable. Lives in the analysis thread, so the callbacks share no state.
+#[derive(Debug, Default)]
+struct StreamEpoch(Option<StreamInstant>);
+
+impl StreamEpoch {
+    fn seconds(&mut self, instant: StreamInstant) -> f64 {
+        let epoch = *self.0.get_or_insert(instant);
+
+        match instant.duration_since(&epoch) {
+            Some(since_epoch) => since_epoch.as_secs_f64(),
+            None => -epoch
+                .duration_since(&instant)
+                .expect("one of the instants is earlier")
+                .as_secs_f64(),
+        }
+    }
+}
+
+/// Find input and output devices by their names, falling back to the host's default devices.
+fn find_devices(
+    input_device_name: Option<String>,
+    output_device_name: Option<String>,
+    status: &Status,
+) -> Result<(Device, Device)> {
     let host = cpal::default_host();
 
     let output_device = match output_device_name {
@@ -41,62 +748,61 @@ pub fn run_real_world_audio(
             .wrap_err("getting default input device")?,
     };
 
-    println!(
-        "choosing {} 🔊 -> 🎤 {}",
-        output_device.name().as_deref().unwrap_or("no name"),
-        input_device.name().as_deref().unwrap_or("no name"),
+    status.set(
+        "devices",
+        format!(
+            "{} 🔊 -> 🎤 {}",
+            output_device.name().as_deref().unwrap_or("no name"),
+            input_device.name().as_deref().unwrap_or("no name"),
+        ),
     );
 
-    let input_config = input_device.default_input_config()?;
-    let output_config = output_device.default_output_config()?;
+    Ok((input_device, output_device))
+}
 
-    dbg!(&input_config);
-    dbg!(&output_config);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    assert_eq!(input_config.sample_rate(), output_config.sample_rate());
-    assert_eq!(input_config.channels(), 1);
-    assert_eq!(input_config.sample_format(), SampleFormat::F32);
-    assert_eq!(output_config.sample_format(), SampleFormat::F32);
+    #[test]
+    fn reports_dropped_frames_once_there_is_room() {
+        let (producer, mut consumer) = queue(2);
+        let mut sender = EventSender::new(producer);
 
-    let computer_for_output = Arc::clone(&computer);
-    let output_channels = output_config.channels() as usize;
-    let output_stream = output_device.build_output_stream(
-        &output_config.into(),
-        move |output: &mut [f32], _info| {
-            let mut computer = computer_for_output.write().unwrap();
+        (0..5).for_each(|frame| sender.send(Event::Frame(frame)));
+        assert!(matches!(consumer.pop(), Some(Event::Frame(0))));
+        assert!(matches!(consumer.pop(), Some(Event::Frame(1))));
+        assert!(consumer.pop().is_none());
 
-            assert_eq!(output.len() % output_channels, 0);
-            output
-                .chunks_exact_mut(output_channels)
-                .for_each(|channels| {
-                    let sample = computer.output_sample();
-                    channels.iter_mut().for_each(|channel| {
-                        *channel = sample;
-                    });
-                });
-        },
-        |err| eprintln!("Error playing audio: {:?}", err),
-        Some(Duration::from_millis(20)),
-    )?;
+        sender.send(Event::Frame(5));
+        assert!(matches!(consumer.pop(), Some(Event::Dropped(3))));
+        assert!(matches!(consumer.pop(), Some(Event::Frame(5))));
+        assert!(consumer.pop().is_none());
+    }
 
-    let computer_for_input = Arc::clone(&computer);
-    let input_stream = input_device.build_input_stream(
-        &input_config.into(),
-        move |data: &[f32], _info| {
-            // TODO: use info timestamps for more accurate delay measurement.
+    #[test]
+    fn realigns_streams_after_overflow() {
+        let mut realignment = Realignment::default();
 
-            let mut computer = computer_for_input.write().unwrap();
-            // Copy data to shared buffer for processing
-            for &sample in data.iter() {
-                computer.record_sample(sample * 100.0);
-            }
-        },
-        |err| eprintln!("Error capturing audio: {:?}", err),
-        Some(Duration::from_millis(20)),
-    )?;
+        realignment.played_dropped(2);
+        assert!(!realignment.skip_played());
+        assert!(realignment.skip_captured());
+        assert!(realignment.skip_captured());
+        assert!(!realignment.skip_captured());
 
-    output_stream.play()?;
-    input_stream.play()?;
+        realignment.captured_dropped(1);
+        assert!(realignment.skip_played());
+        assert!(!realignment.skip_played());
+        assert_eq!(realignment.dropped, 3);
+    }
 
-    Ok((output_stream, input_stream))
+    #[test]
+    fn simultaneous_drops_need_no_realignment() {
+        let mut realignment = Realignment::default();
+
+        realignment.played_dropped(4);
+        realignment.captured_dropped(4);
+        assert!(!realignment.skip_played());
+        assert!(!realignment.skip_captured());
+    }
 }
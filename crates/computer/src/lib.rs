@@ -1,8 +1,22 @@
+pub mod bidirectional;
+pub mod calibration;
 pub mod computer;
+pub mod correlation;
+pub mod drift;
+pub mod excitation;
+pub mod export;
 pub mod gui;
 pub mod io;
+pub mod queue;
+pub mod recording;
+pub mod replay;
 pub mod ring_buffer;
+pub mod service;
 pub mod simulator;
+pub mod status;
+pub mod timing;
+pub mod tracker;
 pub mod tui;
+pub mod units;
 
 pub type Sample = f32;
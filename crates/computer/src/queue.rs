@@ -0,0 +1,187 @@
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Bounded wait-free single-producer single-consumer queue. Neither pushing nor popping locks or
+/// allocates, so it is safe to use from realtime audio callbacks.
+pub fn queue<T: Copy + Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "queue must have non-zero capacity");
+
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        dropped: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            shared: Arc::clone(&shared),
+        },
+        Consumer { shared },
+    )
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Number of elements popped so far. Written only by the consumer.
+    head: AtomicUsize,
+    /// Number of elements pushed so far. Written only by the producer.
+    tail: AtomicUsize,
+    /// Number of elements that didn't fit.
+    dropped: AtomicUsize,
+}
+
+// Safety: the producer only writes slots the consumer has released and the consumer only reads
+// slots the producer has published, synchronized by the head and tail.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index % self.slots.len()].get()
+    }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Producer<T> {
+    /// Push an element to the back of the queue. When the queue is full, drop the element and
+    /// return false.
+    pub fn push(&mut self, element: T) -> bool {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+
+        if tail.wrapping_sub(head) == self.shared.slots.len() {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // Safety: the slot is past the head, so the consumer doesn't read it.
+        unsafe { self.shared.slot(tail).write(MaybeUninit::new(element)) };
+        self.shared
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy> Consumer<T> {
+    /// Pop an element from the front of the queue, if there's any.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        // Safety: the slot is before the tail, so the producer has initialized it and won't
+        // touch it until we advance the head.
+        let element = unsafe { self.shared.slot(head).read().assume_init() };
+        self.shared
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(element)
+    }
+
+    /// Total number of elements the producer dropped because the queue was full.
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the producer is gone, i.e. nothing will be pushed anymore.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn keeps_order_across_wraparound() {
+        let (mut producer, mut consumer) = queue(3);
+
+        // Head and tail go around the slots many times.
+        for round in 0..10 {
+            assert!(producer.push(round * 2));
+            assert!(producer.push(round * 2 + 1));
+            assert_eq!(consumer.pop(), Some(round * 2));
+            assert_eq!(consumer.pop(), Some(round * 2 + 1));
+            assert_eq!(consumer.pop(), None);
+        }
+        assert_eq!(consumer.dropped(), 0);
+    }
+
+    #[test]
+    fn drops_elements_that_dont_fit() {
+        let (mut producer, mut consumer) = queue(2);
+
+        assert!(producer.push(1));
+        assert!(producer.push(2));
+        assert!(!producer.push(3));
+        assert!(!producer.push(4));
+        assert_eq!(consumer.dropped(), 2);
+
+        assert_eq!(consumer.pop(), Some(1));
+        assert!(producer.push(5));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(5));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn is_abandoned_once_producer_is_gone() {
+        let (producer, consumer) = queue::<u8>(1);
+        assert!(!consumer.is_abandoned());
+
+        drop(producer);
+        assert!(consumer.is_abandoned());
+    }
+
+    #[test]
+    fn passes_elements_between_threads() {
+        let (mut producer, mut consumer) = queue(16);
+        let count = 100_000;
+
+        let producer_thread = thread::spawn(move || {
+            let mut failed_pushes = 0;
+            for element in 0..count {
+                while !producer.push(element) {
+                    failed_pushes += 1;
+                    thread::yield_now();
+                }
+            }
+            failed_pushes
+        });
+
+        let mut expected = 0;
+        while expected < count {
+            match consumer.pop() {
+                Some(element) => {
+                    assert_eq!(element, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        // Retried elements count as dropped.
+        assert_eq!(producer_thread.join().unwrap(), consumer.dropped());
+    }
+}
@@ -0,0 +1,573 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    iter,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use eyre::{bail, Context, Result};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    computer::{Computer, DelayResult, SearchLock},
+    status::Status,
+    Sample,
+};
+
+/// Number of chunks the writer thread may fall behind by. A chunk usually holds the samples of a
+/// track drained from the audio queues at once, a few milliseconds of audio.
+const RECORDER_BACKLOG: usize = 1024;
+
+/// Names of the files in a session directory.
+pub const OUTPUT_FILE: &str = "output.wav";
+pub const INPUT_FILE: &str = "input.wav";
+pub const LOOPBACK_FILE: &str = "loopback.wav";
+pub const METADATA_FILE: &str = "session.json";
+
+/// Audio of a measurement session: the signal played from the speaker and the one recorded by the
+/// microphone, both at the same sample rate and starting at the same instant.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub sample_rate_hz: f64,
+    pub reference: Vec<Sample>,
+    pub recorded: Vec<Sample>,
+    /// The electrical loopback of the played signal, if it was recorded.
+    pub loopback: Option<Vec<Sample>>,
+}
+
+impl Recording {
+    /// Load the played and the recorded signal from separate WAV files. Only the first channel of
+    /// each file is used.
+    pub fn from_files(reference_path: &Path, recorded_path: &Path) -> Result<Self> {
+        let (reference_rate, mut reference) = read_wav(reference_path)?;
+        let (recorded_rate, mut recorded) = read_wav(recorded_path)?;
+
+        if reference_rate != recorded_rate {
+            bail!(
+                "sample rates of {} ({reference_rate} Hz) and {} ({recorded_rate} Hz) differ",
+                reference_path.display(),
+                recorded_path.display()
+            );
+        }
+
+        Ok(Self {
+            sample_rate_hz: reference_rate as f64,
+            reference: reference.swap_remove(0),
+            recorded: recorded.swap_remove(0),
+            loopback: None,
+        })
+    }
+
+    /// Load a stereo WAV file with the played signal in the first channel and the recorded one in
+    /// the second.
+    pub fn from_stereo_file(path: &Path) -> Result<Self> {
+        let (sample_rate, mut channels) = read_wav(path)?;
+        if channels.len() < 2 {
+            bail!(
+                "{} has a single channel, expected the played and the recorded signal",
+                path.display()
+            );
+        }
+        channels.truncate(2);
+        let recorded = channels.pop().expect("there are two channels");
+        let reference = channels.pop().expect("there are two channels");
+
+        Ok(Self {
+            sample_rate_hz: sample_rate as f64,
+            reference,
+            recorded,
+            loopback: None,
+        })
+    }
+
+    /// Number of samples present in all the signals. Anything past the end of the shortest one
+    /// can't be correlated.
+    pub fn length(&self) -> usize {
+        let length = self.reference.len().min(self.recorded.len());
+        self.loopback
+            .as_ref()
+            .map_or(length, |loopback| length.min(loopback.len()))
+    }
+
+    /// Load a session written by [SessionRecorder], including its loopback if there is one.
+    pub fn from_session(directory: &Path) -> Result<Self> {
+        let mut recording =
+            Self::from_files(&directory.join(OUTPUT_FILE), &directory.join(INPUT_FILE))?;
+
+        let loopback_path = directory.join(LOOPBACK_FILE);
+        if loopback_path.exists() {
+            let (sample_rate, mut loopback) = read_wav(&loopback_path)?;
+            if sample_rate as f64 != recording.sample_rate_hz {
+                bail!(
+                    "sample rate of {} ({sample_rate} Hz) differs from the rest of the session",
+                    loopback_path.display()
+                );
+            }
+            recording.loopback = Some(loopback.swap_remove(0));
+        }
+
+        Ok(recording)
+    }
+}
+
+/// Feed the recording through the computer, `hop_samples` at a time, and hand over every delay
+/// result. Set the computer's sample rate to that of the recording. Call `before_hop` with the
+/// index of the sample ending each hop before feeding it, e.g. to pace the hops.
+pub fn analyze(
+    computer: &mut Computer,
+    recording: &Recording,
+    hop_samples: usize,
+    mut before_hop: impl FnMut(usize),
+    mut on_result: impl FnMut(&Computer, DelayResult),
+) {
+    assert!(hop_samples > 0, "hop must be positive");
+    computer.set_sample_rate_hz(recording.sample_rate_hz);
+
+    let mut lock = SearchLock::default();
+    let length = recording.length();
+    for start in (0..length).step_by(hop_samples) {
+        let end = (start + hop_samples).min(length);
+        before_hop(end);
+
+        computer.record_output_samples(&recording.reference[start..end]);
+        computer.record_samples(&recording.recorded[start..end]);
+        if let Some(loopback) = recording.loopback.as_ref() {
+            computer.record_loopback_samples(&loopback[start..end]);
+        }
+
+        if let Some(result) = computer.tracking_delay(&mut lock) {
+            on_result(computer, result);
+        }
+    }
+}
+
+/// Description of a recorded session, stored next to the WAV files.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionMetadata {
+    /// Filled in when the recording starts.
+    pub sample_rate_hz: f64,
+    /// When the recording started.
+    pub started: SystemTime,
+    /// Names of the devices the session was recorded with, None when simulating.
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    /// Seed of the excitation and simulator random number generators, if they were seeded.
+    pub seed: Option<u64>,
+    /// Configuration and constants the session ran with.
+    pub parameters: serde_json::Value,
+}
+
+impl SessionMetadata {
+    /// Load the metadata of a session written by [SessionRecorder].
+    pub fn load(directory: &Path) -> Result<Self> {
+        let path = directory.join(METADATA_FILE);
+        let contents = fs::read_to_string(&path)
+            .wrap_err_with(|| format!("reading session metadata {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("parsing session metadata {}", path.display()))
+    }
+}
+
+/// Writes the samples played and recorded by a computer to WAV files in a directory. Sample `n`
+/// of each file is the sample with absolute index `n`, see [Computer::output_sample_count].
+/// Files are created with the first sample, once the sample rate is surely known.
+///
+/// The files are written by a thread of their own so that the audio threads only copy the samples.
+/// When the thread falls behind, samples are dropped rather than queued without bound, and written
+/// as silence to keep the files aligned. Clones feed the same files, which are finished by
+/// [SessionRecorder::finish] or once the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct SessionRecorder {
+    writer: Arc<WriterThread>,
+    /// Shared with the writer thread, which writes it out with the first samples.
+    metadata: Arc<Mutex<SessionMetadata>>,
+}
+
+/// Samples of a track, the sample rate they were recorded at and how many samples of the track were
+/// dropped right before them.
+#[derive(Debug)]
+struct Chunk {
+    track: Track,
+    samples: Vec<Sample>,
+    sample_rate_hz: f64,
+    dropped_samples: usize,
+}
+
+#[derive(Debug)]
+struct WriterThread {
+    /// None once finished.
+    feed: Mutex<Option<Feed>>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// Sending end of the channel to the writer thread.
+#[derive(Debug)]
+struct Feed {
+    sender: SyncSender<Chunk>,
+    /// Samples of each track that didn't fit into the channel since the last chunk of the track
+    /// that did.
+    dropped_samples: [usize; 3],
+}
+
+impl WriterThread {
+    fn finish(&self) {
+        // Closing the channel lets the thread write the remaining samples and finish the files.
+        self.feed.lock().unwrap().take();
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            // A panic of the thread has already been reported.
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for WriterThread {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Which signal a sample belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Track {
+    Output,
+    Input,
+    Loopback,
+}
+
+impl SessionRecorder {
+    /// Record into given directory, creating it if needed. Errors are reported to the status.
+    pub fn new(directory: PathBuf, metadata: SessionMetadata, status: Status) -> Result<Self> {
+        fs::create_dir_all(&directory)
+            .wrap_err_with(|| format!("creating recording directory {}", directory.display()))?;
+
+        let (sender, receiver) = mpsc::sync_channel(RECORDER_BACKLOG);
+        let metadata = Arc::new(Mutex::new(metadata));
+        let writer = SessionWriter {
+            directory,
+            metadata: Arc::clone(&metadata),
+            writers: None,
+            dropped_samples: 0,
+        };
+        let handle = thread::Builder::new()
+            .name("session recorder".to_string())
+            .spawn(move || writer.run(receiver, &status))
+            .wrap_err("spawning session recorder thread")?;
+
+        Ok(Self {
+            writer: Arc::new(WriterThread {
+                feed: Mutex::new(Some(Feed {
+                    sender,
+                    dropped_samples: [0; 3],
+                })),
+                handle: Mutex::new(Some(handle)),
+            }),
+            metadata,
+        })
+    }
+
+    /// Note the names of the devices in the metadata. Call before recording the first sample.
+    pub fn set_devices(&self, input_device: Option<String>, output_device: Option<String>) {
+        let mut metadata = self.metadata.lock().unwrap();
+        metadata.input_device = input_device;
+        metadata.output_device = output_device;
+    }
+
+    /// Append samples to given track. Errors are reported once by the writer thread, which then
+    /// stops recording. Ignored once finished.
+    pub fn record(&self, track: Track, samples: &[Sample], sample_rate_hz: f64) {
+        let mut feed = self.writer.feed.lock().unwrap();
+        let Some(feed) = feed.as_mut().filter(|_| !samples.is_empty()) else {
+            return;
+        };
+
+        let dropped_samples = &mut feed.dropped_samples[track as usize];
+        let chunk = Chunk {
+            track,
+            samples: samples.to_vec(),
+            sample_rate_hz,
+            dropped_samples: *dropped_samples,
+        };
+        match feed.sender.try_send(chunk) {
+            Ok(()) => *dropped_samples = 0,
+            // The writer thread fell behind, it writes silence instead of the samples.
+            Err(TrySendError::Full(_)) => *dropped_samples += samples.len(),
+            // The writer thread stopped on an error.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Write the remaining samples and finish the files. Samples recorded afterwards, by any of
+    /// the clones, are ignored. Call when done, as clones owned by a running computer may never be
+    /// dropped.
+    pub fn finish(&self) {
+        self.writer.finish();
+    }
+}
+
+/// The part of [SessionRecorder] living on the writer thread.
+struct SessionWriter {
+    directory: PathBuf,
+    metadata: Arc<Mutex<SessionMetadata>>,
+    writers: Option<Writers>,
+    /// Samples written as silence so far, as they didn't fit into the channel.
+    dropped_samples: usize,
+}
+
+struct Writers {
+    output: WavWriter<BufWriter<File>>,
+    input: WavWriter<BufWriter<File>>,
+    loopback: Option<WavWriter<BufWriter<File>>>,
+    /// Output samples written since the headers were last updated.
+    unflushed_samples: usize,
+}
+
+impl SessionWriter {
+    /// Write chunks until the channel closes or writing fails, then finish the files.
+    fn run(mut self, receiver: Receiver<Chunk>, status: &Status) {
+        let result = receiver
+            .iter()
+            .try_for_each(|chunk| {
+                if chunk.dropped_samples > 0 {
+                    self.dropped_samples += chunk.dropped_samples;
+                    status.set(
+                        "recording",
+                        format!(
+                            "fell behind, {} samples written as silence so far",
+                            self.dropped_samples
+                        ),
+                    );
+                }
+                self.record(chunk)
+            })
+            .and_then(|()| self.finish());
+
+        if let Err(err) = result {
+            status.set(
+                "recording",
+                format!("writing to {} failed: {err:#}", self.directory.display()),
+            );
+        }
+    }
+
+    fn record(&mut self, chunk: Chunk) -> Result<()> {
+        let Chunk {
+            track,
+            samples,
+            sample_rate_hz,
+            dropped_samples,
+        } = chunk;
+        if self.writers.is_none() {
+            self.writers = Some(self.start(sample_rate_hz)?);
+        }
+        let writers = self.writers.as_mut().expect("writers were just created");
+
+        let writer = match track {
+            Track::Output => &mut writers.output,
+            Track::Input => &mut writers.input,
+            Track::Loopback => match writers.loopback.as_mut() {
+                Some(loopback) => loopback,
+                None => writers.loopback.insert(create_wav(
+                    &self.directory,
+                    LOOPBACK_FILE,
+                    sample_rate_hz,
+                )?),
+            },
+        };
+        // Silence in place of the dropped samples keeps the tracks aligned.
+        for sample in iter::repeat_n(0.0, dropped_samples).chain(samples.iter().copied()) {
+            writer.write_sample(sample)?;
+        }
+
+        if track == Track::Output {
+            writers.unflushed_samples += dropped_samples + samples.len();
+            // Update the headers every second so that the files are valid even if the program
+            // gets killed.
+            if writers.unflushed_samples as f64 >= sample_rate_hz {
+                writers.output.flush()?;
+                writers.input.flush()?;
+                if let Some(loopback) = writers.loopback.as_mut() {
+                    loopback.flush()?;
+                }
+                writers.unflushed_samples = 0;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn start(&mut self, sample_rate_hz: f64) -> Result<Writers> {
+        let metadata = SessionMetadata {
+            sample_rate_hz,
+            started: SystemTime::now(),
+            ..self.metadata.lock().unwrap().clone()
+        };
+
+        let path = self.directory.join(METADATA_FILE);
+        let contents =
+            serde_json::to_string_pretty(&metadata).wrap_err("serializing session metadata")?;
+        fs::write(&path, contents)
+            .wrap_err_with(|| format!("writing session metadata {}", path.display()))?;
+
+        Ok(Writers {
+            output: create_wav(&self.directory, OUTPUT_FILE, sample_rate_hz)?,
+            input: create_wav(&self.directory, INPUT_FILE, sample_rate_hz)?,
+            loopback: None,
+            unflushed_samples: 0,
+        })
+    }
+
+    /// Write the final headers, if anything was recorded at all.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(writers) = self.writers.take() {
+            writers.output.finalize()?;
+            writers.input.finalize()?;
+            if let Some(loopback) = writers.loopback {
+                loopback.finalize()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn create_wav(
+    directory: &Path,
+    file_name: &str,
+    sample_rate_hz: f64,
+) -> Result<WavWriter<BufWriter<File>>> {
+    let path = directory.join(file_name);
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate: sample_rate_hz.round() as u32,
+        bits_per_sample: 32,
+        sample_format: SampleFormat::Float,
+    };
+
+    WavWriter::create(&path, spec).wrap_err_with(|| format!("creating WAV file {}", path.display()))
+}
+
+/// Read all channels of a WAV file, converted to floating point samples. Return its sample rate
+/// too.
+fn read_wav(path: &Path) -> Result<(u32, Vec<Vec<Sample>>)> {
+    let reader =
+        WavReader::open(path).wrap_err_with(|| format!("opening WAV file {}", path.display()))?;
+    let spec = reader.spec();
+
+    let interleaved: Vec<Sample> = match spec.sample_format {
+        SampleFormat::Float => reader.into_samples::<f32>().collect::<Result<_, _>>(),
+        SampleFormat::Int => {
+            // Scale integers of any width to -1..1.
+            let scale = 1.0 / (1_i64 << (spec.bits_per_sample - 1)) as Sample;
+            reader
+                .into_samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as Sample * scale))
+                .collect()
+        }
+    }
+    .wrap_err_with(|| format!("reading WAV file {}", path.display()))?;
+
+    let channel_count = spec.channels as usize;
+    let channels = (0..channel_count)
+        .map(|channel| {
+            interleaved
+                .iter()
+                .skip(channel)
+                .step_by(channel_count)
+                .copied()
+                .collect()
+        })
+        .collect();
+
+    Ok((spec.sample_rate, channels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> SessionMetadata {
+        SessionMetadata {
+            sample_rate_hz: 0.0,
+            started: SystemTime::UNIX_EPOCH,
+            input_device: None,
+            output_device: None,
+            seed: Some(1),
+            parameters: serde_json::json!({ "comparison_window_width": 1024 }),
+        }
+    }
+
+    #[test]
+    fn recorded_session_loads_back() {
+        let directory = std::env::temp_dir().join(format!("session-{}", std::process::id()));
+        let output: Vec<Sample> = (0..3000)
+            .map(|index| (index as Sample * 0.01).sin())
+            .collect();
+        let input: Vec<Sample> = output.iter().map(|sample| 0.5 * sample).collect();
+
+        let recorder = SessionRecorder::new(directory.clone(), metadata(), Status::new()).unwrap();
+        recorder.set_devices(Some("microphone".to_string()), Some("speaker".to_string()));
+        let clone = recorder.clone();
+        for start in (0..output.len()).step_by(1000) {
+            recorder.record(Track::Output, &output[start..start + 1000], 1000.0);
+            clone.record(Track::Input, &input[start..start + 1000], 1000.0);
+            clone.record(Track::Loopback, &output[start..start + 1000], 1000.0);
+        }
+        // Finished while a clone is still around. What it records afterwards is ignored.
+        recorder.finish();
+        clone.record(Track::Output, &output, 1000.0);
+
+        let recording = Recording::from_session(&directory).unwrap();
+        let loaded = SessionMetadata::load(&directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(recording.sample_rate_hz, 1000.0);
+        assert_eq!(recording.reference, output);
+        assert_eq!(recording.recorded, input);
+        assert_eq!(recording.loopback, Some(output));
+        assert_eq!(loaded.sample_rate_hz, 1000.0);
+        assert_eq!(loaded.input_device.as_deref(), Some("microphone"));
+        assert_eq!(loaded.output_device.as_deref(), Some("speaker"));
+        assert_eq!(loaded.parameters, metadata().parameters);
+    }
+
+    #[test]
+    fn dropped_samples_are_written_as_silence() {
+        let directory = std::env::temp_dir().join(format!("dropped-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let mut writer = SessionWriter {
+            directory: directory.clone(),
+            metadata: Arc::new(Mutex::new(metadata())),
+            writers: None,
+            dropped_samples: 0,
+        };
+
+        for (track, samples, dropped_samples) in [
+            (Track::Output, vec![1.0, 2.0], 0),
+            (Track::Output, vec![3.0], 2),
+            (Track::Input, vec![4.0, 5.0, 6.0, 7.0, 8.0], 0),
+        ] {
+            writer
+                .record(Chunk {
+                    track,
+                    samples,
+                    sample_rate_hz: 1000.0,
+                    dropped_samples,
+                })
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let recording = Recording::from_session(&directory).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(recording.reference, [1.0, 2.0, 0.0, 0.0, 3.0]);
+        assert_eq!(recording.length(), 5);
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    computer::Computer,
+    recording::{analyze, Recording},
+    service::{Analysis, MeasurementService},
+    status::Status,
+    units::Setup,
+};
+
+/// How often to report the progress of the replay.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How fast to feed a recording to the computer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Multiple of the pace the recording was made at, 1.0 is real time.
+    Factor(f64),
+    /// Don't wait at all. Limited only by how fast the delays are computed.
+    AsFastAsPossible,
+}
+
+/// Spawn a thread that feeds the recording to the computer, `hop_samples` at a time, and publishes
+/// an analysis of every hop to the service. See [analyze]. The progress is reported to the status.
+///
+/// Unlike the live [MeasurementService::spawn] worker, which analyses whatever the computer holds
+/// when it gets to it, every hop is analysed exactly once and no subscriber misses any analysis.
+/// Replaying the same recording therefore produces the same delays, regardless of the speed and
+/// the machine.
+pub fn replay_recording(
+    mut computer: Computer,
+    recording: Recording,
+    hop_samples: usize,
+    speed: ReplaySpeed,
+    setup: Setup,
+    service: MeasurementService,
+    status: Status,
+) -> JoinHandle<()> {
+    assert!(hop_samples > 0, "hop must be positive");
+    if let ReplaySpeed::Factor(factor) = speed {
+        assert!(factor > 0.0, "speed factor must be positive");
+    }
+
+    thread::spawn(move || {
+        let duration_s = recording.length() as f64 / recording.sample_rate_hz;
+        let start_instant = Instant::now();
+        let mut last_progress = start_instant;
+        let pace = |end: usize| {
+            if let ReplaySpeed::Factor(factor) = speed {
+                // Release the hop when its last sample would have been recorded.
+                let due = Duration::from_secs_f64(end as f64 / recording.sample_rate_hz / factor);
+                thread::sleep(due.saturating_sub(start_instant.elapsed()));
+            }
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                let replayed_s = end as f64 / recording.sample_rate_hz;
+                status.set("replay", format!("{replayed_s:.1} of {duration_s:.1} s"));
+                last_progress = Instant::now();
+            }
+        };
+
+        analyze(
+            &mut computer,
+            &recording,
+            hop_samples,
+            pace,
+            |computer, result| {
+                service.publish_blocking(Analysis {
+                    measurement: computer.measurement(&result, setup),
+                    computer: computer.clone(),
+                    result,
+                });
+            },
+        );
+
+        status.set(
+            "replay",
+            format!(
+                "finished {duration_s:.1} s of audio in {:.1} s",
+                start_instant.elapsed().as_secs_f64()
+            ),
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::Sample;
+
+    fn recording() -> Recording {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let reference: Vec<Sample> = (0..20_000).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        let recorded = (0..reference.len())
+            .map(|index| {
+                // Slowly growing delay, noisy.
+                let delay = 100 + index / 2000;
+                index
+                    .checked_sub(delay)
+                    .map_or(0.0, |index| reference[index])
+                    + rng.gen_range(-0.5..0.5)
+            })
+            .collect();
+
+        Recording {
+            sample_rate_hz: 48_000.0,
+            reference,
+            recorded,
+            loopback: None,
+        }
+    }
+
+    fn replayed_delays(speed: ReplaySpeed) -> Vec<(f64, f64)> {
+        let service = MeasurementService::new();
+        let analyses = service.subscribe();
+        let computer = Computer::new(256, 1024).with_tracking_search(8, 0.2);
+        replay_recording(
+            computer,
+            recording(),
+            700,
+            speed,
+            Setup::default(),
+            service,
+            Status::new(),
+        )
+        .join()
+        .unwrap();
+
+        analyses
+            .iter()
+            .map(|analysis| {
+                (
+                    analysis.result.precise_delay_samples,
+                    analysis.result.quality.confidence(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_are_identical() {
+        let first = replayed_delays(ReplaySpeed::AsFastAsPossible);
+        // Every hop but the first, which doesn't fill the comparison window, is analysed.
+        assert_eq!(first.len(), 20_000_usize.div_ceil(700) - 1);
+        assert_eq!(first, replayed_delays(ReplaySpeed::AsFastAsPossible));
+        assert_eq!(first, replayed_delays(ReplaySpeed::Factor(100.0)));
+    }
+}
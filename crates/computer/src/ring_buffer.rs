@@ -37,6 +37,20 @@ impl<T> RingBuffer<T> {
         Some(front_element)
     }
 
+    /// Push all elements to the back of the buffer, popping as many elements from the front as
+    /// needed to stay within capacity.
+    pub fn extend_from_slice(&mut self, elements: &[T])
+    where
+        T: Clone,
+    {
+        // Only the last `capacity` elements can stay in the buffer.
+        let elements = &elements[elements.len().saturating_sub(self.capacity)..];
+        let overflow = (self.inner.len() + elements.len()).saturating_sub(self.capacity);
+
+        self.inner.drain(..overflow);
+        self.inner.extend(elements.iter().cloned());
+    }
+
     pub fn len(&self) -> usize {
         self.inner.len()
     }
@@ -53,6 +67,21 @@ impl<T> RingBuffer<T> {
         self.inner.iter()
     }
 
+    /// Contents of the buffer, from the front, as two contiguous slices.
+    pub fn as_slices(&self) -> (&[T], &[T]) {
+        self.inner.as_slices()
+    }
+
+    /// Elements from given index to the back, as two contiguous slices.
+    pub fn slices_from(&self, start: usize) -> (&[T], &[T]) {
+        let (front, back) = self.inner.as_slices();
+        if start < front.len() {
+            (&front[start..], back)
+        } else {
+            (&back[start - front.len()..], &[])
+        }
+    }
+
     pub fn capacity(&self) -> usize {
         self.capacity
     }
@@ -67,3 +96,77 @@ impl<T> RingBuffer<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Buffer of given capacity whose contents wrap around the end of the backing storage.
+    fn wrapped(capacity: usize, pushed: std::ops::Range<u32>) -> RingBuffer<u32> {
+        let mut buffer = RingBuffer::new(capacity);
+        pushed.for_each(|element| {
+            buffer.push_back(element);
+        });
+        buffer
+    }
+
+    fn joined((front, back): (&[u32], &[u32])) -> Vec<u32> {
+        [front, back].concat()
+    }
+
+    #[test]
+    fn push_back_pops_front_when_full() {
+        let mut buffer = RingBuffer::new(2);
+        assert_eq!(buffer.push_back(1), None);
+        assert_eq!(buffer.push_back(2), None);
+        assert!(buffer.is_full());
+        assert_eq!(buffer.push_back(3), Some(1));
+        assert_eq!(buffer.iter().copied().collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn extend_from_slice_matches_push_back() {
+        for (initial, extension) in [(0, 3), (3, 4), (5, 2), (2, 9), (0, 12)] {
+            let mut pushed = wrapped(5, 0..initial);
+            let mut extended = pushed.clone();
+
+            (initial..initial + extension).for_each(|element| {
+                pushed.push_back(element);
+            });
+            extended.extend_from_slice(&(initial..initial + extension).collect::<Vec<_>>());
+
+            assert_eq!(
+                extended.iter().collect::<Vec<_>>(),
+                pushed.iter().collect::<Vec<_>>(),
+                "{initial} then {extension} elements"
+            );
+            assert_eq!(extended.len(), pushed.len());
+        }
+    }
+
+    #[test]
+    fn slices_from_wrapped_buffer() {
+        let buffer = wrapped(5, 0..8);
+        assert_eq!(joined(buffer.as_slices()), [3, 4, 5, 6, 7]);
+
+        for start in 0..=buffer.len() {
+            assert_eq!(
+                joined(buffer.slices_from(start)),
+                buffer.iter().skip(start).copied().collect::<Vec<_>>(),
+                "from {start}"
+            );
+        }
+    }
+
+    #[test]
+    fn set_capacity_pops_front() {
+        let mut buffer = wrapped(5, 0..5);
+        buffer.set_capacity(3);
+        assert_eq!(joined(buffer.as_slices()), [2, 3, 4]);
+        assert!(buffer.is_full());
+
+        buffer.set_capacity(4);
+        assert!(!buffer.is_full());
+        assert_eq!(buffer.push_back(5), None);
+    }
+}
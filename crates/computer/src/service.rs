@@ -0,0 +1,251 @@
+use std::{
+    ops::Deref,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    bidirectional::{BidirectionalComputer, BidirectionalResult},
+    computer::{Computer, DelayResult, SearchLock},
+    units::{Measurement, Setup},
+};
+
+/// How many analyses a subscriber may lag behind before it starts missing them.
+const SUBSCRIBER_BACKLOG: usize = 64;
+
+/// Result of analysing a single snapshot of the computer.
+#[derive(Debug)]
+pub struct Analysis {
+    /// The snapshot the result was computed on.
+    pub computer: Computer,
+    pub result: DelayResult,
+    pub measurement: Measurement,
+}
+
+/// Result of analysing a single snapshot of the bidirectional computer.
+#[derive(Debug)]
+pub struct BidirectionalAnalysis {
+    /// The snapshot the result was computed on.
+    pub computer: BidirectionalComputer,
+    pub result: BidirectionalResult,
+}
+
+/// Single worker computing delays of the computer over and over and publishing them to any
+/// number of subscribers. Keeps the expensive correlation from running once per consumer and all
+/// consumers see the same results. The worker stops once the service and all its clones are
+/// dropped, which ends the subscriptions too.
+#[derive(Debug)]
+pub struct MeasurementService<A = Analysis> {
+    subscribers: Arc<Mutex<Vec<SyncSender<Arc<A>>>>>,
+}
+
+impl MeasurementService {
+    /// Spawn the worker. Delays are converted to physical units with given setup.
+    pub fn spawn(computer: Arc<RwLock<Computer>>, setup: Setup) -> Self {
+        let mut lock = SearchLock::default();
+
+        Self::spawn_worker(computer, Computer::input_sample_count, move |computer| {
+            let result = computer.tracking_delay(&mut lock)?;
+            Some(Analysis {
+                measurement: computer.measurement(&result, setup),
+                computer,
+                result,
+            })
+        })
+    }
+}
+
+impl MeasurementService<BidirectionalAnalysis> {
+    /// Spawn the worker measuring both paths of the bidirectional computer.
+    pub fn spawn_bidirectional(computer: Arc<RwLock<BidirectionalComputer>>) -> Self {
+        let mut locks = [SearchLock::default(); 2];
+
+        Self::spawn_worker(
+            computer,
+            BidirectionalComputer::input_sample_count,
+            move |computer| {
+                let result = computer.tracking_measure(&mut locks)?;
+                Some(BidirectionalAnalysis { computer, result })
+            },
+        )
+    }
+}
+
+impl<A: Send + Sync + 'static> MeasurementService<A> {
+    /// Service without a worker. Analyses are published by whoever drives it, see
+    /// [MeasurementService::publish].
+    pub fn new() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Spawn a worker publishing analyses of snapshots of the computer, one per batch of newly
+    /// recorded input samples as counted by `input_sample_count`. `analyse` returns None until
+    /// the computer has enough samples.
+    fn spawn_worker<C: Clone + Send + Sync + 'static>(
+        computer: Arc<RwLock<C>>,
+        input_sample_count: fn(&C) -> u64,
+        mut analyse: impl FnMut(C) -> Option<A> + Send + 'static,
+    ) -> Self {
+        let service = Self::new();
+
+        // Not to keep the service alive, the worker holds on to the subscribers only to publish.
+        let subscribers = Arc::downgrade(&service.subscribers);
+        let mut last_input_samples = None;
+        thread::spawn(move || {
+            while subscribers.strong_count() > 0 {
+                // Computing the delay() is much more expensive than cloning the entire computer.
+                // To lower lock contention, copy a snapshot of the computer to this thread
+                // and immediately release the lock. Analysing the same samples again would only
+                // duplicate the previous result, so don't even copy the computer until new ones
+                // come.
+                let snapshot = {
+                    let computer = computer.read().unwrap();
+                    let samples = input_sample_count(&computer);
+                    (last_input_samples != Some(samples))
+                        .then(|| (computer.deref().clone(), samples))
+                };
+                let Some((computer, samples)) = snapshot else {
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                };
+
+                let Some(analysis) = analyse(computer) else {
+                    // The computer is not ready yet. Give it some time to accumulate more samples.
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                };
+                last_input_samples = Some(samples);
+
+                if let Some(subscribers) = subscribers.upgrade() {
+                    Self { subscribers }.publish(analysis);
+                }
+            }
+        });
+
+        service
+    }
+
+    /// Send the analysis to all subscribers.
+    pub fn publish(&self, analysis: A) {
+        let analysis = Arc::new(analysis);
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.try_send(Arc::clone(&analysis)) {
+                // A slow subscriber misses the analysis, but keeps the subscription.
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+
+    /// Send the analysis to all subscribers, waiting for the slow ones. Unlike
+    /// [MeasurementService::publish], every subscriber receives every analysis.
+    pub fn publish_blocking(&self, analysis: A) {
+        let analysis = Arc::new(analysis);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(Arc::clone(&analysis)).is_ok());
+    }
+
+    /// Receive all analyses from now on. Drop the receiver to unsubscribe.
+    pub fn subscribe(&self) -> Receiver<Arc<A>> {
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BACKLOG);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+// Not derived, the analyses themselves don't need to be cloneable.
+impl<A> Clone for MeasurementService<A> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: Arc::clone(&self.subscribers),
+        }
+    }
+}
+
+impl<A: Send + Sync + 'static> Default for MeasurementService<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sample;
+
+    #[test]
+    fn analyses_new_samples_only() {
+        let samples: Vec<Sample> = (0..2048).map(|index| (index as Sample).sin()).collect();
+        let computer = Arc::new(RwLock::new(Computer::new(256, 1024)));
+        let service = MeasurementService::spawn(Arc::clone(&computer), Setup::default());
+        let analyses = service.subscribe();
+
+        {
+            let mut computer = computer.write().unwrap();
+            computer.record_output_samples(&samples);
+            computer.record_samples(&samples);
+        }
+        let analysis = analyses.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(analysis.result.input_samples.end, 2048);
+        assert!(analyses.recv_timeout(Duration::from_millis(200)).is_err());
+
+        computer.write().unwrap().record_samples(&samples[..100]);
+        let analysis = analyses.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(analysis.result.input_samples.end, 2148);
+    }
+
+    #[test]
+    fn analyses_new_bidirectional_frames_only() {
+        let setup = Setup {
+            path_length_m: Some(1.0),
+            system_latency_s: 0.0,
+        };
+        let computer = Arc::new(RwLock::new(BidirectionalComputer::new(
+            Computer::new(256, 1024),
+            Computer::new(256, 1024),
+            setup,
+        )));
+        let service = MeasurementService::spawn_bidirectional(Arc::clone(&computer));
+        let analyses = service.subscribe();
+
+        {
+            let mut computer = computer.write().unwrap();
+            for _ in 0..2048 {
+                let frame = computer.output_frame();
+                computer.record_frame(frame);
+            }
+        }
+        let analysis = analyses.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(analysis.result.forward.input_samples.end, 2048);
+        assert_eq!(analysis.result.backward.input_samples.end, 2048);
+        // Without new frames there's nothing new to publish, unconfident results included.
+        assert!(analyses.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+
+    #[test]
+    fn worker_stops_with_the_service() {
+        let computer = Arc::new(RwLock::new(Computer::new(256, 1024)));
+        let service = MeasurementService::spawn(Arc::clone(&computer), Setup::default());
+        let analyses = service.subscribe();
+        drop(service);
+
+        assert!(analyses.recv_timeout(Duration::from_secs(5)).is_err());
+        // The worker lets go of the computer as it stops.
+        let start = std::time::Instant::now();
+        while Arc::strong_count(&computer) > 1 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "worker keeps running"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -1,21 +1,29 @@
 use std::{
     sync::{Arc, RwLock},
     thread,
-    time::{Duration, Instant},
 };
 
-use rand::random;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-use crate::{computer::Computer, ring_buffer::RingBuffer, Sample};
+use crate::{
+    bidirectional::BidirectionalComputer, computer::Computer, ring_buffer::RingBuffer, Sample,
+};
+
+/// Number of samples the simulation advances by at once.
+const BLOCK_SAMPLES: usize = 256;
 
 #[derive(Debug)]
 pub struct Simulator {
     delay_buffer: Option<RingBuffer<Sample>>,
+    /// Source of the noise.
+    rng: ChaCha8Rng,
     pub gain: f32,
     pub signal_to_noise_ratio: f32,
 }
 
 impl Simulator {
+    /// Simulator with noise seeded from the operating system's entropy.
     pub fn new(delay_samples: usize, gain: f32, signal_to_noise_ratio: f32) -> Self {
         let delay_buffer = if delay_samples > 0 {
             Some(RingBuffer::new(delay_samples))
@@ -25,11 +33,18 @@ impl Simulator {
 
         Self {
             delay_buffer,
+            rng: ChaCha8Rng::from_entropy(),
             gain,
             signal_to_noise_ratio,
         }
     }
 
+    /// Draw the noise from given generator. A seeded one makes the simulation repeatable.
+    pub fn with_rng(mut self, rng: ChaCha8Rng) -> Self {
+        self.rng = rng;
+        self
+    }
+
     pub fn tick(&mut self, input: Sample) -> Sample {
         let output = match self.delay_buffer.as_mut() {
             // Delay is zero, let the sample just pass through.
@@ -38,7 +53,7 @@ impl Simulator {
             Some(buffer) => buffer.push_back(input).unwrap_or(0.0),
         };
 
-        let noise = random::<f32>() / self.signal_to_noise_ratio;
+        let noise = self.rng.gen::<f32>() / self.signal_to_noise_ratio;
 
         output * self.gain + noise
     }
@@ -59,40 +74,76 @@ impl Simulator {
     }
 }
 
-/// Spawn a thread that advances the simulator and the computer.
+/// Spawn a thread that advances the simulator and the computer. The simulator draws noise from
+/// `rng`.
 pub fn simulate_audio_pipeline(
     computer: Arc<RwLock<Computer>>,
     delay_samples: usize,
     gain: f32,
     signal_to_noise_ratio: f32,
+    rng: ChaCha8Rng,
 ) -> Arc<RwLock<Simulator>> {
-    let simulator = Arc::new(RwLock::new(Simulator::new(
-        delay_samples,
-        gain,
-        signal_to_noise_ratio,
-    )));
+    let simulator = Arc::new(RwLock::new(
+        Simulator::new(delay_samples, gain, signal_to_noise_ratio).with_rng(rng),
+    ));
 
     {
         let simulator = Arc::clone(&simulator);
         thread::spawn(move || {
-            let mut samples = 0;
-            let mut last_report = Instant::now();
+            let mut output = [0.0; BLOCK_SAMPLES];
+            let mut input = [0.0; BLOCK_SAMPLES];
             loop {
-                // TODO: maybe better to lock the computer only once?
-                let output_sample = computer.write().unwrap().output_sample();
-                let input_sample = simulator.write().unwrap().tick(output_sample);
-                computer.write().unwrap().record_sample(input_sample);
-
-                samples += 1;
-
-                if last_report.elapsed() > Duration::from_secs(1) {
-                    println!("processed {samples} samples");
-                    samples = 0;
-                    last_report = Instant::now();
-                }
+                // Hold the lock for the whole block so that nobody sees the output without the
+                // corresponding input.
+                let mut computer = computer.write().unwrap();
+                computer.fill_output(&mut output);
+
+                let mut simulator = simulator.write().unwrap();
+                input
+                    .iter_mut()
+                    .zip(output)
+                    .for_each(|(input, output)| *input = simulator.tick(output));
+
+                computer.record_samples(&input);
             }
         });
     }
 
     simulator
 }
+
+/// Spawn a thread that advances simulators of both paths and the bidirectional computer.
+/// Return the simulators of the forward and the backward path. Their generators are seeded from
+/// `rng`.
+pub fn simulate_bidirectional_audio_pipeline(
+    computer: Arc<RwLock<BidirectionalComputer>>,
+    forward_delay_samples: usize,
+    backward_delay_samples: usize,
+    gain: f32,
+    signal_to_noise_ratio: f32,
+    mut rng: ChaCha8Rng,
+) -> [Arc<RwLock<Simulator>>; 2] {
+    let simulators = [forward_delay_samples, backward_delay_samples].map(|delay_samples| {
+        Arc::new(RwLock::new(
+            Simulator::new(delay_samples, gain, signal_to_noise_ratio)
+                .with_rng(ChaCha8Rng::seed_from_u64(rng.gen())),
+        ))
+    });
+
+    {
+        let [forward, backward] = simulators.clone();
+        thread::spawn(move || loop {
+            // See simulate_audio_pipeline() for why we lock for the whole block.
+            let mut computer = computer.write().unwrap();
+            let mut forward = forward.write().unwrap();
+            let mut backward = backward.write().unwrap();
+            for _ in 0..BLOCK_SAMPLES {
+                let [forward_output, backward_output] = computer.output_frame();
+                computer
+                    .record_frame([forward.tick(forward_output), backward.tick(backward_output)]);
+            }
+        });
+    }
+
+    simulators
+}
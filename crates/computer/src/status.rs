@@ -0,0 +1,71 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Latest message of each background activity, e.g. the clock drift estimate, dropped audio frames
+/// or the progress of a replay. Messages are printed to the standard error as they come, unless a
+/// dashboard shows them instead, as prints would paint over it. Clones share the messages.
+#[derive(Debug, Clone, Default)]
+pub struct Status {
+    inner: Arc<Mutex<Messages>>,
+}
+
+#[derive(Debug, Default)]
+struct Messages {
+    by_topic: BTreeMap<&'static str, String>,
+    shown: bool,
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the message of given topic.
+    pub fn set(&self, topic: &'static str, message: impl Into<String>) {
+        let message = message.into();
+        let mut messages = self.inner.lock().unwrap();
+        if !messages.shown {
+            eprintln!("{topic}: {message}");
+        }
+        messages.by_topic.insert(topic, message);
+    }
+
+    /// Tell whether a dashboard shows the messages. They aren't printed in the meantime.
+    pub fn set_shown(&self, shown: bool) {
+        self.inner.lock().unwrap().shown = shown;
+    }
+
+    /// Latest message of every topic, ordered by the topic.
+    pub fn messages(&self) -> Vec<(&'static str, String)> {
+        let messages = self.inner.lock().unwrap();
+        messages
+            .by_topic
+            .iter()
+            .map(|(topic, message)| (*topic, message.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_latest_message_of_each_topic() {
+        let status = Status::new();
+        status.set_shown(true);
+        status.set("replay", "1.0 of 2.0 s");
+        status.clone().set("clock drift", "1.00 ppm");
+        status.set("replay", "2.0 of 2.0 s");
+
+        assert_eq!(
+            status.messages(),
+            [
+                ("clock drift", "1.00 ppm".to_string()),
+                ("replay", "2.0 of 2.0 s".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,29 @@
+/// Weight of a new timestamp in the running estimate of the stream origin. Smooths out the jitter
+/// of callback scheduling.
+const SMOOTHING: f64 = 0.05;
+
+/// Maps sample indices of an audio stream to the instants the samples were played or captured.
+/// Instants are in seconds on a clock shared by all streams of the host, with an arbitrary epoch.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamClock {
+    /// Instant at which the sample with index zero was played or captured.
+    origin_s: Option<f64>,
+}
+
+impl StreamClock {
+    /// Incorporate a timestamp of the sample with given index, e.g. the first one of a buffer.
+    pub fn tag(&mut self, sample_index: u64, instant_s: f64, sample_rate_hz: f64) {
+        let origin_s = instant_s - sample_index as f64 / sample_rate_hz;
+
+        self.origin_s = Some(match self.origin_s {
+            Some(previous_s) => previous_s + SMOOTHING * (origin_s - previous_s),
+            None => origin_s,
+        });
+    }
+
+    /// Instant at which the sample with given index was (or will be) played or captured. None
+    /// until the first timestamp arrives.
+    pub fn instant_s(&self, sample_index: u64, sample_rate_hz: f64) -> Option<f64> {
+        Some(self.origin_s? + sample_index as f64 / sample_rate_hz)
+    }
+}
@@ -0,0 +1,236 @@
+use crate::computer::DelayResult;
+
+/// Parameters of the [Tracker].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackerConfig {
+    /// Standard deviation (in samples) of a single delay measurement.
+    pub measurement_std_samples: f64,
+    /// Spectral density (in samples²/s³) of the random acceleration of the delay.
+    /// Higher values let the tracker follow faster changes, at the cost of more noise.
+    pub process_noise: f64,
+    /// Measurements further than this many standard deviations from the prediction are outliers.
+    pub gate_sigmas: f64,
+    /// After this many outliers in a row the tracker assumes it lost the delay and starts over.
+    pub max_consecutive_outliers: usize,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        Self {
+            measurement_std_samples: 0.5,
+            process_noise: 10.0,
+            gate_sigmas: 4.0,
+            max_consecutive_outliers: 10,
+        }
+    }
+}
+
+/// Kalman filter following the delay and its rate of change across successive measurements.
+/// Uses constant velocity model, i.e. the delay changes linearly with random acceleration.
+#[derive(Debug, Clone)]
+pub struct Tracker {
+    config: TrackerConfig,
+    state: Option<State>,
+    consecutive_outliers: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// Delay (in samples) and its rate of change (in samples per second).
+    x: [f64; 2],
+    /// Covariance of the state.
+    p: [[f64; 2]; 2],
+}
+
+/// Result of feeding a single measurement to the [Tracker].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackedDelay {
+    /// The measured delay as it was fed to the tracker.
+    pub raw_delay_samples: f64,
+    /// Filtered delay.
+    pub delay_samples: f64,
+    pub delay_std_samples: f64,
+    /// Rate at which the delay changes.
+    pub rate_samples_per_s: f64,
+    pub rate_std_samples_per_s: f64,
+    /// Whether the measurement was rejected by innovation gating and didn't affect the estimate.
+    pub outlier: bool,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Self {
+            config,
+            state: None,
+            consecutive_outliers: 0,
+        }
+    }
+
+    /// Advance the filter by `elapsed_s` seconds since the previous result and incorporate the
+    /// precise delay of the new one.
+    pub fn update(&mut self, result: &DelayResult, elapsed_s: f64) -> TrackedDelay {
+        self.update_delay(result.precise_delay_samples, elapsed_s)
+    }
+
+    /// Like [Tracker::update], but with the delay given directly.
+    pub fn update_delay(&mut self, delay_samples: f64, elapsed_s: f64) -> TrackedDelay {
+        let measurement_variance = self.config.measurement_std_samples.powi(2);
+
+        let Some(mut state) = self.state else {
+            return self.restart(delay_samples);
+        };
+
+        state.predict(elapsed_s, self.config.process_noise);
+
+        let innovation = delay_samples - state.x[0];
+        let innovation_variance = state.p[0][0] + measurement_variance;
+        let outlier = innovation.powi(2) > self.config.gate_sigmas.powi(2) * innovation_variance;
+
+        if outlier {
+            self.consecutive_outliers += 1;
+            if self.consecutive_outliers > self.config.max_consecutive_outliers {
+                // The delay has likely jumped. Lock on the new value.
+                return self.restart(delay_samples);
+            }
+        } else {
+            self.consecutive_outliers = 0;
+            state.correct(innovation, innovation_variance);
+        }
+
+        self.state = Some(state);
+        state.tracked(delay_samples, outlier)
+    }
+
+    /// Forget the tracked delay.
+    pub fn reset(&mut self) {
+        self.state = None;
+        self.consecutive_outliers = 0;
+    }
+
+    fn restart(&mut self, delay_samples: f64) -> TrackedDelay {
+        let state = State {
+            x: [delay_samples, 0.0],
+            // We know nothing about the rate yet, hence the huge variance.
+            p: [
+                [self.config.measurement_std_samples.powi(2), 0.0],
+                [0.0, 1e6],
+            ],
+        };
+        self.state = Some(state);
+        self.consecutive_outliers = 0;
+
+        state.tracked(delay_samples, false)
+    }
+}
+
+impl State {
+    fn predict(&mut self, elapsed_s: f64, process_noise: f64) {
+        let dt = elapsed_s;
+        let [[p00, p01], [p10, p11]] = self.p;
+
+        self.x[0] += self.x[1] * dt;
+
+        // P = F P F^T + Q where F = [[1, dt], [0, 1]].
+        self.p = [
+            [
+                p00 + dt * (p01 + p10) + dt * dt * p11 + process_noise * dt.powi(3) / 3.0,
+                p01 + dt * p11 + process_noise * dt.powi(2) / 2.0,
+            ],
+            [
+                p10 + dt * p11 + process_noise * dt.powi(2) / 2.0,
+                p11 + process_noise * dt,
+            ],
+        ];
+    }
+
+    fn correct(&mut self, innovation: f64, innovation_variance: f64) {
+        let [[p00, p01], [p10, p11]] = self.p;
+        let gain = [p00 / innovation_variance, p10 / innovation_variance];
+
+        self.x[0] += gain[0] * innovation;
+        self.x[1] += gain[1] * innovation;
+
+        // P = (I - K H) P where H = [1, 0].
+        self.p = [
+            [(1.0 - gain[0]) * p00, (1.0 - gain[0]) * p01],
+            [p10 - gain[1] * p00, p11 - gain[1] * p01],
+        ];
+    }
+
+    fn tracked(&self, raw_delay_samples: f64, outlier: bool) -> TrackedDelay {
+        TrackedDelay {
+            raw_delay_samples,
+            delay_samples: self.x[0],
+            delay_std_samples: self.p[0][0].sqrt(),
+            rate_samples_per_s: self.x[1],
+            rate_std_samples_per_s: self.p[1][1].sqrt(),
+            outlier,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL_S: f64 = 0.1;
+
+    /// Tracker locked on a steady delay of 100 samples.
+    fn locked_tracker() -> Tracker {
+        let mut tracker = Tracker::new(TrackerConfig::default());
+        for index in 0..50 {
+            let noise = if index % 2 == 0 { 0.2 } else { -0.2 };
+            tracker.update_delay(100.0 + noise, INTERVAL_S);
+        }
+        tracker
+    }
+
+    #[test]
+    fn follows_steady_delay() {
+        let tracked = locked_tracker().update_delay(100.0, INTERVAL_S);
+
+        assert!(!tracked.outlier);
+        assert!((tracked.delay_samples - 100.0).abs() < 0.1);
+        assert!(tracked.rate_samples_per_s.abs() < 1.0);
+    }
+
+    #[test]
+    fn gates_outlier() {
+        let mut tracker = locked_tracker();
+
+        let tracked = tracker.update_delay(500.0, INTERVAL_S);
+        assert!(tracked.outlier);
+        assert_eq!(tracked.raw_delay_samples, 500.0);
+        // The outlier doesn't move the estimate.
+        assert!((tracked.delay_samples - 100.0).abs() < 0.1);
+
+        assert!(!tracker.update_delay(100.0, INTERVAL_S).outlier);
+    }
+
+    #[test]
+    fn relocks_after_consecutive_outliers() {
+        let mut tracker = locked_tracker();
+        let max_consecutive_outliers = TrackerConfig::default().max_consecutive_outliers;
+
+        for _ in 0..max_consecutive_outliers {
+            assert!(tracker.update_delay(500.0, INTERVAL_S).outlier);
+        }
+
+        let tracked = tracker.update_delay(500.0, INTERVAL_S);
+        assert!(!tracked.outlier);
+        assert_eq!(tracked.delay_samples, 500.0);
+        assert!(!tracker.update_delay(500.0, INTERVAL_S).outlier);
+    }
+
+    #[test]
+    fn first_measurement_is_taken_as_is() {
+        let mut tracker = Tracker::new(TrackerConfig::default());
+
+        let tracked = tracker.update_delay(42.0, INTERVAL_S);
+        assert!(!tracked.outlier);
+        assert_eq!(tracked.delay_samples, 42.0);
+
+        tracker.reset();
+        assert_eq!(tracker.update_delay(7.0, INTERVAL_S).delay_samples, 7.0);
+    }
+}
@@ -1,49 +1,684 @@
 use std::{
-    collections::BTreeMap,
-    ops::Deref,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, RwLock,
+    },
     thread,
     time::{Duration, Instant},
 };
 
-use crate::computer::{Computer, DelayResult};
+use eyre::{Context, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Bar, BarChart, BarGroup, Block, Gauge, Paragraph, Sparkline},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    bidirectional::{BidirectionalResult, Wind},
+    computer::Quality,
+    ring_buffer::RingBuffer,
+    service::{Analysis, BidirectionalAnalysis},
+    simulator::Simulator,
+    status::Status,
+    tracker::{TrackedDelay, Tracker, TrackerConfig},
+    units::{Measurement, Setup},
+    Sample,
+};
+
+/// How often the dashboard redraws and checks for key presses.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of the latest measurements shown in the history and the histogram.
+const HISTORY_LENGTH: usize = 1024;
+/// Signal level shown as an empty gauge.
+const LEVEL_FLOOR_DB: f64 = -60.0;
+/// Period the bidirectional dashboard averages the wind over.
+const AVERAGING_PERIOD: Duration = Duration::from_secs(1);
+
+/// Full-screen dashboard of the measurements: the latest and the tracked delay, their history and
+/// histogram, signal levels, confidence and parameters of the simulator if there is one. Redraws
+/// in place until `q`, `Esc` or `Ctrl+C` is pressed, also after the analyses stop coming.
+/// Results with confidence below `minimum_confidence` are left out of the statistics. Messages of
+/// the status are shown instead of printed meanwhile.
+pub fn run_tui(
+    analyses: Receiver<Arc<Analysis>>,
+    minimum_confidence: f64,
+    tracker_config: TrackerConfig,
+    histogram_bucket_samples: f64,
+    simulator: Option<Arc<RwLock<Simulator>>>,
+    status: Status,
+) -> Result<()> {
+    assert!(
+        histogram_bucket_samples > 0.0,
+        "histogram buckets must have positive width"
+    );
+
+    let mut dashboard = Dashboard {
+        minimum_confidence,
+        histogram_bucket_samples,
+        simulator,
+        tracker: Tracker::new(tracker_config),
+        tracked: None,
+        history: VecDeque::with_capacity(HISTORY_LENGTH),
+        last: None,
+        measurements: 0,
+        rejected: 0,
+        outliers: 0,
+        last_update_samples: None,
+        speed: None,
+        speed_since: None,
+    };
+
+    show(&mut dashboard, &analyses, &status)
+}
+
+/// Full-screen dashboard of the bidirectional measurements: wind speed and speed of sound averaged
+/// every second, the latest delays of both paths, their confidence and input levels. Measurements
+/// where any of the paths has confidence below `minimum_confidence` are left out of the averages.
+/// Otherwise like [run_tui].
+pub fn run_bidirectional_tui(
+    analyses: Receiver<Arc<BidirectionalAnalysis>>,
+    minimum_confidence: f64,
+    status: Status,
+) -> Result<()> {
+    let mut dashboard = BidirectionalDashboard {
+        minimum_confidence,
+        winds: Vec::new(),
+        rejected: 0,
+        period_start: Instant::now(),
+        average: None,
+        last: None,
+    };
+
+    show(&mut dashboard, &analyses, &status)
+}
 
-pub fn run_tui(computer: Arc<RwLock<Computer>>) -> ! {
-    let mut measurements = Vec::new();
-    let mut last_report = Instant::now();
+/// Contents of a full-screen dashboard of analyses of type `A`, see [show].
+trait View<A> {
+    fn update(&mut self, analysis: Arc<A>);
+    fn render(&self, frame: &mut Frame, area: Rect);
+}
+
+/// Show the view in the terminal, with the status below it, until the user quits.
+fn show<A>(view: &mut impl View<A>, analyses: &Receiver<Arc<A>>, status: &Status) -> Result<()> {
+    let mut terminal = match ratatui::try_init() {
+        Ok(terminal) => terminal,
+        Err(err) => {
+            // Undo whatever part of the initialization succeeded.
+            ratatui::restore();
+            return Err(err)
+                .wrap_err("starting the TUI, use --export when not running in a terminal");
+        }
+    };
+
+    // Prints would paint over the dashboard.
+    status.set_shown(true);
+    let result = run(&mut terminal, view, analyses, status);
+    ratatui::restore();
+    status.set_shown(false);
+    result
+}
+
+fn run<A>(
+    terminal: &mut DefaultTerminal,
+    view: &mut impl View<A>,
+    analyses: &Receiver<Arc<A>>,
+    status: &Status,
+) -> Result<()> {
+    // The analyses stopped coming, e.g. because a replay finished.
+    let mut finished = false;
     loop {
-        // Computing the delay() is much more expensive than cloning the entire computer.
-        // To lower lock contention, copy a snapshot of the computer to this thread
-        // and immediately release the lock.
-        let computer = computer.read().unwrap().deref().clone();
-
-        if let Some(DelayResult { delay_samples, .. }) = computer.delay() {
-            measurements.push(delay_samples);
-
-            if last_report.elapsed() > Duration::from_secs(1) {
-                let avg = measurements.iter().sum::<usize>() as f64 / measurements.len() as f64;
-                measurements.sort();
-                let histogram =
-                    measurements
-                        .iter()
-                        .fold(BTreeMap::new(), |mut buckets, measurement| {
-                            let bucket = measurement / 100;
-                            let entry = buckets.entry(bucket).or_insert(0u32);
-                            *entry += 1;
-                            buckets
-                        });
-
-                println!(
-                    "avg: {avg} samples (averaged over {} measurments)",
-                    measurements.len()
-                );
-                println!("histogram: {:#?}", histogram);
-                measurements.drain(..);
-                last_report = Instant::now();
+        // Take in analyses until the next redraw.
+        let frame_start = Instant::now();
+        loop {
+            let remaining = REDRAW_INTERVAL.saturating_sub(frame_start.elapsed());
+            if remaining.is_zero() {
+                break;
+            }
+            if finished {
+                thread::sleep(remaining);
+                break;
+            }
+
+            match analyses.recv_timeout(remaining) {
+                Ok(analysis) => view.update(analysis),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => finished = true,
             }
+        }
+
+        // Resizes need no handling, the terminal adapts to the new size on the next draw.
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                let quit = match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => true,
+                    KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+                    _ => false,
+                };
+                if quit && key.kind == KeyEventKind::Press {
+                    return Ok(());
+                }
+            }
+        }
+
+        terminal.draw(|frame| render(frame, view, status, finished))?;
+    }
+}
+
+fn render<A>(frame: &mut Frame, view: &impl View<A>, status: &Status, finished: bool) {
+    let messages = status.messages();
+    let status_height = if messages.is_empty() {
+        0
+    } else {
+        messages.len() as u16 + 2
+    };
+    let [view_area, status_area, footer] = Layout::vertical([
+        Constraint::Min(0),
+        Constraint::Length(status_height),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    view.render(frame, view_area);
+
+    if !messages.is_empty() {
+        let lines: Vec<Line> = messages
+            .into_iter()
+            .map(|(topic, message)| Line::from(format!("{topic}: {message}")))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Status")),
+            status_area,
+        );
+    }
+
+    let hint = if finished {
+        "analyses finished, press q to quit"
+    } else {
+        "press q to quit"
+    };
+    frame.render_widget(
+        Line::from(hint).style(Style::new().fg(Color::DarkGray)),
+        footer,
+    );
+}
+
+struct Dashboard {
+    minimum_confidence: f64,
+    histogram_bucket_samples: f64,
+    simulator: Option<Arc<RwLock<Simulator>>>,
+    tracker: Tracker,
+    tracked: Option<TrackedDelay>,
+    /// The latest confident measurements, each with its delay relative to the loopback, see
+    /// [crate::computer::DelayResult::relative_delay_samples]. The plots show the relative delay,
+    /// as the loopback cancels the device latency.
+    history: VecDeque<(Measurement, f64)>,
+    last: Option<Arc<Analysis>>,
+    measurements: usize,
+    rejected: usize,
+    outliers: usize,
+    /// Input samples of the last update, to time the tracker by the audio rather than the wall
+    /// clock. That way replays of a recording are tracked the same no matter how fast they run.
+    last_update_samples: Option<u64>,
+    /// How many times faster than real time the audio is processed, e.g. by the simulator.
+    speed: Option<f64>,
+    speed_since: Option<(Instant, u64)>,
+}
+
+impl View<Analysis> for Dashboard {
+    fn update(&mut self, analysis: Arc<Analysis>) {
+        let Analysis {
+            computer,
+            result,
+            measurement,
+        } = analysis.as_ref();
+
+        if result.is_confident(self.minimum_confidence) {
+            let elapsed_s = self.last_update_samples.map_or(0.0, |last_update| {
+                (result.input_samples.end - last_update) as f64 / computer.sample_rate_hz()
+            });
+            let tracked = self.tracker.update(result, elapsed_s);
+            self.last_update_samples = Some(result.input_samples.end);
+            if tracked.outlier {
+                self.outliers += 1;
+            }
+            self.tracked = Some(tracked);
+
+            if self.history.len() == HISTORY_LENGTH {
+                self.history.pop_front();
+            }
+            self.history
+                .push_back((*measurement, result.relative_delay_samples()));
+            self.measurements += 1;
         } else {
-            // The computer is not ready yet. Give it some time to accumulate more samples.
-            thread::sleep(Duration::from_millis(100));
+            self.rejected += 1;
+        }
+
+        let now = Instant::now();
+        match self.speed_since {
+            Some((since, samples)) if now.duration_since(since) >= Duration::from_secs(1) => {
+                let audio_s =
+                    (result.input_samples.end - samples) as f64 / computer.sample_rate_hz();
+                self.speed = Some(audio_s / now.duration_since(since).as_secs_f64());
+                self.speed_since = Some((now, result.input_samples.end));
+            }
+            Some(_) => {}
+            None => self.speed_since = Some((now, result.input_samples.end)),
+        }
+
+        self.last = Some(analysis);
+    }
+
+    fn render(&self, frame: &mut Frame, area: Rect) {
+        let simulator_height = if self.simulator.is_some() { 3 } else { 0 };
+        let [summary, confidence, levels, history, histogram, simulator] = Layout::vertical([
+            Constraint::Length(6),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Min(5),
+            Constraint::Min(8),
+            Constraint::Length(simulator_height),
+        ])
+        .areas(area);
+
+        self.render_summary(frame, summary);
+        frame.render_widget(
+            confidence_gauge(
+                "Confidence",
+                self.last.as_ref().map(|analysis| &analysis.result.quality),
+                self.minimum_confidence,
+            ),
+            confidence,
+        );
+        let [input, output] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(levels);
+        let computer = self.last.as_ref().map(|analysis| &analysis.computer);
+        frame.render_widget(
+            level_gauge(
+                "Input level",
+                computer.map(|computer| computer.input_buffer()),
+            ),
+            input,
+        );
+        frame.render_widget(
+            level_gauge(
+                "Output level",
+                computer.map(|computer| computer.output_buffer()),
+            ),
+            output,
+        );
+        self.render_history(frame, history);
+        self.render_histogram(frame, histogram);
+        self.render_simulator(frame, simulator);
+    }
+}
+
+impl Dashboard {
+    fn render_summary(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Delay");
+        let Some(analysis) = self.last.as_ref() else {
+            frame.render_widget(
+                Paragraph::new("waiting for the first measurement…").block(block),
+                area,
+            );
+            return;
+        };
+
+        let mut lines = vec![Line::from(format!("last: {}", analysis.measurement))];
+        if let Some(average) = self.average() {
+            lines.push(Line::from(format!(
+                "average: {average} (of the last {} measurements)",
+                self.history.len()
+            )));
         }
+        if let Some(tracked) = self.tracked {
+            lines.push(Line::from(format!(
+                "tracked: {:.2} ± {:.2} samples, rate {:.2} ± {:.2} samples/s",
+                tracked.delay_samples,
+                tracked.delay_std_samples,
+                tracked.rate_samples_per_s,
+                tracked.rate_std_samples_per_s,
+            )));
+        }
+
+        let mut counts = format!(
+            "{} measurements, {} rejected, {} outliers",
+            self.measurements, self.rejected, self.outliers
+        );
+        if let Some(stream_latency_s) = analysis.computer.stream_latency_s() {
+            counts += &format!(", stream latency {:.3} ms", stream_latency_s * 1000.0);
+        }
+        if let Some(speed) = self.speed {
+            counts += &format!(", {speed:.1}× real time");
+        }
+        lines.push(Line::from(counts));
+
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn render_history(&self, frame: &mut Frame, area: Rect) {
+        // Show as many of the latest delays as fit, stretched between their minimum and maximum.
+        let shown = self
+            .history
+            .iter()
+            .rev()
+            .take(area.width.saturating_sub(2) as usize)
+            .rev()
+            .map(|(_, relative_delay_samples)| *relative_delay_samples);
+        let (minimum, maximum) = shown
+            .clone()
+            .fold((f64::MAX, f64::MIN), |(min, max), delay| {
+                (min.min(delay), max.max(delay))
+            });
+        // Quantize to hundredths of a sample.
+        let data: Vec<u64> = shown
+            .map(|delay| ((delay - minimum) * 100.0).round() as u64 + 1)
+            .collect();
+
+        let title = if data.is_empty() {
+            "History".to_string()
+        } else {
+            format!("History ({minimum:.2} to {maximum:.2} samples)")
+        };
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(title))
+                .style(Style::new().fg(Color::Yellow))
+                .data(&data),
+            area,
+        );
+    }
+
+    fn render_histogram(&self, frame: &mut Frame, area: Rect) {
+        let buckets = self.history.iter().fold(
+            BTreeMap::new(),
+            |mut buckets, (_, relative_delay_samples)| {
+                let bucket = (relative_delay_samples / self.histogram_bucket_samples).floor();
+                *buckets.entry(bucket as i64).or_insert(0) += 1;
+                buckets
+            },
+        );
+        let bars: Vec<Bar> = buckets
+            .into_iter()
+            .map(|(bucket, count)| {
+                Bar::default().value(count).label(Line::from(format!(
+                    "{}",
+                    bucket as f64 * self.histogram_bucket_samples
+                )))
+            })
+            .collect();
+
+        frame.render_widget(
+            BarChart::default()
+                .block(Block::bordered().title(format!(
+                    "Histogram ({} samples per bucket)",
+                    self.histogram_bucket_samples
+                )))
+                .bar_width(6)
+                .bar_gap(1)
+                .bar_style(Style::new().fg(Color::Magenta))
+                .data(BarGroup::default().bars(&bars)),
+            area,
+        );
+    }
+
+    fn render_simulator(&self, frame: &mut Frame, area: Rect) {
+        let Some(simulator) = self.simulator.as_ref() else {
+            return;
+        };
+        let simulator = simulator.read().unwrap();
+
+        frame.render_widget(
+            Paragraph::new(format!(
+                "gain {:.2}, delay {} samples, signal to noise ratio {:.2} \
+                (adjust with A/S, D/F and N/M in the GUI)",
+                simulator.gain,
+                simulator.delay_samples(),
+                simulator.signal_to_noise_ratio
+            ))
+            .block(Block::bordered().title("Simulator")),
+            area,
+        );
+    }
+
+    /// Average of the measurements in the history.
+    fn average(&self) -> Option<Measurement> {
+        let (last, _) = self.history.back()?;
+        let count = self.history.len() as f64;
+
+        Some(Measurement::new(
+            self.history
+                .iter()
+                .map(|(measurement, _)| measurement.delay_samples)
+                .sum::<f64>()
+                / count,
+            last.sample_rate_hz,
+            Setup {
+                // Loopback makes the latency vary, average it too.
+                system_latency_s: self
+                    .history
+                    .iter()
+                    .map(|(measurement, _)| measurement.setup.system_latency_s)
+                    .sum::<f64>()
+                    / count,
+                ..last.setup
+            },
+        ))
+    }
+}
+
+/// Gauge of the confidence of the result with given quality, green when at least the minimum.
+fn confidence_gauge<'a>(
+    title: &'a str,
+    quality: Option<&Quality>,
+    minimum_confidence: f64,
+) -> Gauge<'a> {
+    let (ratio, label) = match quality {
+        Some(quality) => (
+            quality.confidence(),
+            format!(
+                "{:.3} (peak to sidelobe {:.1}, SNR {:.1} dB)",
+                quality.confidence(),
+                quality.peak_to_sidelobe_ratio,
+                quality.snr_db
+            ),
+        ),
+        None => (0.0, String::new()),
+    };
+    let color = if ratio >= minimum_confidence {
+        Color::Green
+    } else {
+        Color::Red
+    };
+
+    Gauge::default()
+        .block(Block::bordered().title(title))
+        .gauge_style(Style::new().fg(color))
+        .ratio(ratio)
+        .label(label)
+}
+
+/// Gauge of the signal level in the buffer, empty when there's no buffer yet.
+fn level_gauge<'a>(title: &'a str, buffer: Option<&RingBuffer<Sample>>) -> Gauge<'a> {
+    let gauge = Gauge::default()
+        .block(Block::bordered().title(title))
+        .gauge_style(Style::new().fg(Color::Cyan));
+    let Some(buffer) = buffer else {
+        return gauge.ratio(0.0).label("");
+    };
+
+    let level_db = rms_db(buffer);
+    gauge
+        .ratio((1.0 - level_db / LEVEL_FLOOR_DB).clamp(0.0, 1.0))
+        .label(format!("{level_db:.1} dBFS"))
+}
+
+/// Root mean square level of the samples relative to full scale.
+fn rms_db(buffer: &RingBuffer<Sample>) -> f64 {
+    if buffer.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+
+    let (first, second) = buffer.as_slices();
+    let energy: f64 = first
+        .iter()
+        .chain(second)
+        .map(|&sample| (sample as f64).powi(2))
+        .sum();
+
+    10.0 * (energy / buffer.len() as f64).log10()
+}
+
+struct BidirectionalDashboard {
+    minimum_confidence: f64,
+    /// Confident measurements of the current averaging period.
+    winds: Vec<Wind>,
+    rejected: usize,
+    period_start: Instant,
+    /// Outcome of the last finished averaging period.
+    average: Option<WindAverage>,
+    last: Option<Arc<BidirectionalAnalysis>>,
+}
+
+/// Wind averaged over a period, None when no measurement of the period was confident.
+struct WindAverage {
+    wind: Option<Wind>,
+    measurements: usize,
+    rejected: usize,
+}
+
+impl View<BidirectionalAnalysis> for BidirectionalDashboard {
+    fn update(&mut self, analysis: Arc<BidirectionalAnalysis>) {
+        let BidirectionalResult {
+            forward,
+            backward,
+            wind,
+        } = &analysis.result;
+        match wind {
+            Some(wind)
+                if forward.is_confident(self.minimum_confidence)
+                    && backward.is_confident(self.minimum_confidence) =>
+            {
+                self.winds.push(*wind)
+            }
+            _ => self.rejected += 1,
+        }
+
+        if self.period_start.elapsed() >= AVERAGING_PERIOD {
+            let count = self.winds.len() as f64;
+            self.average = Some(WindAverage {
+                wind: (!self.winds.is_empty()).then(|| Wind {
+                    speed_m_s: self.winds.iter().map(|wind| wind.speed_m_s).sum::<f64>() / count,
+                    speed_of_sound_m_s: self
+                        .winds
+                        .iter()
+                        .map(|wind| wind.speed_of_sound_m_s)
+                        .sum::<f64>()
+                        / count,
+                }),
+                measurements: self.winds.len(),
+                rejected: self.rejected,
+            });
+            self.winds.clear();
+            self.rejected = 0;
+            self.period_start = Instant::now();
+        }
+
+        self.last = Some(analysis);
+    }
+
+    fn render(&self, frame: &mut Frame, area: Rect) {
+        let [summary, confidence, levels, _] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Min(0),
+        ])
+        .areas(area);
+
+        self.render_summary(frame, summary);
+
+        let result = self.last.as_ref().map(|analysis| &analysis.result);
+        let computer = self.last.as_ref().map(|analysis| &analysis.computer);
+        let [forward, backward] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(confidence);
+        frame.render_widget(
+            confidence_gauge(
+                "Forward confidence",
+                result.map(|result| &result.forward.quality),
+                self.minimum_confidence,
+            ),
+            forward,
+        );
+        frame.render_widget(
+            confidence_gauge(
+                "Backward confidence",
+                result.map(|result| &result.backward.quality),
+                self.minimum_confidence,
+            ),
+            backward,
+        );
+
+        let [forward, backward] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(levels);
+        frame.render_widget(
+            level_gauge(
+                "Forward input level",
+                computer.map(|computer| computer.forward().input_buffer()),
+            ),
+            forward,
+        );
+        frame.render_widget(
+            level_gauge(
+                "Backward input level",
+                computer.map(|computer| computer.backward().input_buffer()),
+            ),
+            backward,
+        );
+    }
+}
+
+impl BidirectionalDashboard {
+    fn render_summary(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title("Wind");
+        let Some(analysis) = self.last.as_ref() else {
+            frame.render_widget(
+                Paragraph::new("waiting for the first measurement…").block(block),
+                area,
+            );
+            return;
+        };
+
+        let average = match self.average.as_ref() {
+            None => "averaging the first second…".to_string(),
+            Some(WindAverage {
+                wind: Some(wind),
+                measurements,
+                rejected,
+            }) => format!(
+                "wind: {:.3} m/s, speed of sound: {:.2} m/s \
+                (averaged over {measurements} measurements, {rejected} rejected)",
+                wind.speed_m_s, wind.speed_of_sound_m_s
+            ),
+            Some(WindAverage {
+                wind: None,
+                rejected,
+                ..
+            }) => format!("no confident measurement ({rejected} rejected)"),
+        };
+        let delays = format!(
+            "last delays: forward {:.2} samples, backward {:.2} samples",
+            analysis.result.forward.precise_delay_samples,
+            analysis.result.backward.precise_delay_samples
+        );
+
+        frame.render_widget(
+            Paragraph::new(vec![Line::from(average), Line::from(delays)]).block(block),
+            area,
+        );
     }
 }
@@ -0,0 +1,133 @@
+use std::fmt;
+
+/// Speed of sound in dry air at 0 °C.
+const SPEED_OF_SOUND_AT_ZERO_CELSIUS_M_S: f64 = 331.3;
+const ZERO_CELSIUS_K: f64 = 273.15;
+
+/// Physical parameters of the measurement setup needed to convert delays into physical quantities.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Setup {
+    /// Distance between the speaker and the microphone.
+    pub path_length_m: Option<f64>,
+    /// Delay introduced by the audio interface, drivers and buffering, i.e. everything but the
+    /// acoustic time of flight.
+    pub system_latency_s: f64,
+}
+
+/// Delay converted to physical units.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measurement {
+    pub delay_samples: f64,
+    pub sample_rate_hz: f64,
+    pub setup: Setup,
+}
+
+impl Measurement {
+    pub fn new(delay_samples: f64, sample_rate_hz: f64, setup: Setup) -> Self {
+        Self {
+            delay_samples,
+            sample_rate_hz,
+            setup,
+        }
+    }
+
+    /// Total delay between playing and recording the signal.
+    pub fn delay_s(&self) -> f64 {
+        self.delay_samples / self.sample_rate_hz
+    }
+
+    /// Time the sound spent travelling through the air, i.e. delay without the system latency.
+    pub fn time_of_flight_s(&self) -> f64 {
+        self.delay_s() - self.setup.system_latency_s
+    }
+
+    /// Apparent speed of sound along the path. None if the path length isn't known or the time of
+    /// flight isn't positive.
+    pub fn speed_of_sound_m_s(&self) -> Option<f64> {
+        let path_length_m = self.setup.path_length_m?;
+        let time_of_flight_s = self.time_of_flight_s();
+
+        (time_of_flight_s > 0.0).then(|| path_length_m / time_of_flight_s)
+    }
+
+    /// Temperature of dry still air in which sound travels at the apparent speed of sound.
+    pub fn temperature_c(&self) -> Option<f64> {
+        self.speed_of_sound_m_s()
+            .map(temperature_from_speed_of_sound_c)
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2} samples, {:.3} ms time of flight",
+            self.delay_samples,
+            self.time_of_flight_s() * 1000.0
+        )?;
+
+        if let (Some(speed_of_sound), Some(temperature)) =
+            (self.speed_of_sound_m_s(), self.temperature_c())
+        {
+            write!(f, ", {speed_of_sound:.2} m/s, {temperature:.1} °C")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Speed of sound in dry still air of given temperature.
+pub fn speed_of_sound_from_temperature_m_s(temperature_c: f64) -> f64 {
+    SPEED_OF_SOUND_AT_ZERO_CELSIUS_M_S * (1.0 + temperature_c / ZERO_CELSIUS_K).sqrt()
+}
+
+/// Invert `c = 331.3 · sqrt(1 + T / 273.15)`, the speed of sound in dry air.
+pub fn temperature_from_speed_of_sound_c(speed_of_sound_m_s: f64) -> f64 {
+    ZERO_CELSIUS_K * ((speed_of_sound_m_s / SPEED_OF_SOUND_AT_ZERO_CELSIUS_M_S).powi(2) - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_round_trips_through_speed_of_sound() {
+        for temperature_c in [-20.0, 0.0, 20.0, 40.0] {
+            let speed_of_sound_m_s = speed_of_sound_from_temperature_m_s(temperature_c);
+            assert!(
+                (temperature_from_speed_of_sound_c(speed_of_sound_m_s) - temperature_c).abs()
+                    < 1e-9
+            );
+        }
+        assert!((speed_of_sound_from_temperature_m_s(20.0) - 343.2).abs() < 0.1);
+    }
+
+    #[test]
+    fn time_of_flight_excludes_system_latency() {
+        let setup = Setup {
+            path_length_m: Some(1.0),
+            system_latency_s: 0.01,
+        };
+        // 10 ms of latency plus 1 m at 343.2 m/s.
+        let delay_samples = (0.01 + 1.0 / 343.2) * 48_000.0;
+        let measurement = Measurement::new(delay_samples, 48_000.0, setup);
+
+        assert!((measurement.time_of_flight_s() - 1.0 / 343.2).abs() < 1e-12);
+        assert!((measurement.speed_of_sound_m_s().unwrap() - 343.2).abs() < 1e-9);
+        assert!((measurement.temperature_c().unwrap() - 20.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn no_speed_of_sound_without_positive_time_of_flight() {
+        let setup = Setup {
+            path_length_m: Some(1.0),
+            system_latency_s: 0.01,
+        };
+        let measurement = Measurement::new(240.0, 48_000.0, setup);
+
+        assert!(measurement.speed_of_sound_m_s().is_none());
+        assert!(Measurement::new(1000.0, 48_000.0, Setup::default())
+            .speed_of_sound_m_s()
+            .is_none());
+    }
+}